@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lockit_fuzz::compression;
+use lockit_fuzz::header::Compression;
+use std::io::{self, Cursor};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = compression::decompress_data(data);
+
+    if let Ok(mut reader) = compression::decompress_reader(Compression::Zstd, Box::new(Cursor::new(data.to_vec()))) {
+        let _ = io::copy(&mut reader, &mut io::sink());
+    }
+});
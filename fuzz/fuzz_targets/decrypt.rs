@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lockit_fuzz::{crypto, keyring, pipe};
+use std::io::{self, Cursor};
+
+fuzz_target!(|data: &[u8]| {
+    let keyring = keyring();
+    let _ = crypto::decrypt_data(data, &keyring);
+
+    if let Ok((_, mut reader)) = crypto::decrypt_reader(Cursor::new(data.to_vec()), &keyring) {
+        let _ = io::copy(&mut reader, &mut io::sink());
+    }
+
+    let _ = pipe::decrypt_pipe(data, io::sink(), &keyring);
+});
@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lockit_fuzz::header;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((header, len)) = header::parse(data) {
+        assert!(len <= data.len());
+        let encoded = header.encode();
+        let (reparsed, _) = header::parse(&encoded).expect("an encoded header parses");
+        assert!(reparsed == header);
+    }
+});
@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lockit_fuzz::metadata::Metadata;
+use std::io::Cursor;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(metadata) = Metadata::decode(data) {
+        let _ = metadata.file_name();
+        let reread = Metadata::read(&mut Cursor::new(metadata.encode())).expect("an encoded block reads");
+        assert!(reread == metadata);
+    }
+});
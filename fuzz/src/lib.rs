@@ -0,0 +1,33 @@
+// lockit is a binary crate, so the decoding modules are compiled here again for the fuzz targets
+#![allow(dead_code)]
+
+#[path = "../../src/compression.rs"]
+pub mod compression;
+#[path = "../../src/config.rs"]
+pub mod config;
+#[path = "../../src/crypto.rs"]
+pub mod crypto;
+#[path = "../../src/error.rs"]
+pub mod error;
+#[path = "../../src/header.rs"]
+pub mod header;
+#[path = "../../src/keyfile.rs"]
+pub mod keyfile;
+#[path = "../../src/metadata.rs"]
+pub mod metadata;
+#[path = "../../src/names.rs"]
+pub mod names;
+#[path = "../../src/pipe.rs"]
+pub mod pipe;
+#[path = "../../src/plan.rs"]
+pub mod plan;
+#[path = "../../src/recipient.rs"]
+pub mod recipient;
+
+use crypto::Keyring;
+use x25519_dalek::StaticSecret;
+
+// A fixed key file key and identity, so well-formed inputs reach the AEAD without running Argon2id
+pub fn keyring() -> Keyring {
+    Keyring::new(None).with_key([7u8; 32]).with_identities(vec![StaticSecret::from([9u8; 32])])
+}
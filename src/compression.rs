@@ -1,10 +1,50 @@
-use std::io;
+use std::io::{self, Read, Write};
 use zstd::stream::{encode_all, decode_all};
+use zstd::stream::read::Decoder;
+use zstd::stream::write::Encoder;
 
-pub fn compress_data(data: &[u8]) -> io::Result<Vec<u8>> {
-    encode_all(data, 0)
+use crate::error::{LockitError, Result};
+use crate::header::Compression;
+
+pub fn compress_data(data: &[u8]) -> Result<Vec<u8>> {
+    Ok(encode_all(data, 0)?)
+}
+
+pub fn decompress_data(data: &[u8]) -> Result<Vec<u8>> {
+    decode_all(data).map_err(corrupt)
+}
+
+pub fn decompress_with(codec: Compression, data: Vec<u8>) -> Result<Vec<u8>> {
+    match codec {
+        Compression::None => Ok(data),
+        Compression::Zstd => decompress_data(&data),
+    }
+}
+
+pub fn compress_writer<W: Write>(writer: W) -> Result<Encoder<'static, W>> {
+    Ok(Encoder::new(writer, 0)?)
+}
+
+pub fn decompress_reader(codec: Compression, reader: Box<dyn Read>) -> Result<Box<dyn Read>> {
+    match codec {
+        Compression::None => Ok(reader),
+        Compression::Zstd => Ok(Box::new(DecompressReader(Decoder::new(reader)?))),
+    }
+}
+
+// zstd reports damaged frames as plain I/O errors, while errors from the decrypting reader
+// underneath already carry their class
+struct DecompressReader<R: Read>(R);
+
+impl<R: Read> Read for DecompressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf).map_err(|e| corrupt(e).into())
+    }
 }
 
-pub fn decompress_data(data: &[u8]) -> io::Result<Vec<u8>> {
-    decode_all(data)
+fn corrupt(e: io::Error) -> LockitError {
+    match LockitError::from(e) {
+        LockitError::Io(e) if e.kind() == io::ErrorKind::Other => LockitError::Corrupt(format!("Cannot decompress: {}", e)),
+        e => e,
+    }
 }
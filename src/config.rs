@@ -0,0 +1,263 @@
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::crypto;
+use crate::header::Cipher;
+
+const PROJECT_CONFIG_FILE: &str = ".lockit.toml";
+const ENV_PREFIX: &str = "LOCKIT_";
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Origin {
+    BuiltIn,
+    File(PathBuf),
+    Environment(String),
+    CommandLine,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Origin::BuiltIn => write!(f, "built-in"),
+            Origin::File(path) => write!(f, "{}", path.display()),
+            Origin::Environment(name) => write!(f, "environment {}", name),
+            Origin::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Setting<T> {
+    pub value: T,
+    pub origin: Origin,
+}
+
+impl<T> Setting<T> {
+    fn built_in(value: T) -> Self {
+        Setting { value, origin: Origin::BuiltIn }
+    }
+
+    pub fn override_with(&mut self, value: Option<T>) {
+        if let Some(value) = value {
+            self.value = value;
+            self.origin = Origin::CommandLine;
+        }
+    }
+}
+
+trait FromEnv: Sized {
+    fn from_env(value: &str) -> Option<Self>;
+}
+
+impl FromEnv for String {
+    fn from_env(value: &str) -> Option<Self> {
+        Some(value.to_string())
+    }
+}
+
+impl FromEnv for bool {
+    fn from_env(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Some(true),
+            "0" | "false" | "no" | "off" => Some(false),
+            _ => None,
+        }
+    }
+}
+
+impl FromEnv for u32 {
+    fn from_env(value: &str) -> Option<Self> {
+        value.parse().ok()
+    }
+}
+
+// Every setting can be given as a `key = value` in a config file or as a `LOCKIT_KEY` variable
+macro_rules! settings {
+    ($($field:ident: $type:ty = $default:expr,)*) => {
+        #[derive(Clone, Debug)]
+        pub struct Settings {
+            $(pub $field: Setting<$type>,)*
+        }
+
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct FileSettings {
+            $($field: Option<$type>,)*
+        }
+
+        impl Settings {
+            fn built_in() -> Self {
+                Settings { $($field: Setting::built_in($default),)* }
+            }
+
+            fn apply_file(&mut self, file: FileSettings, origin: &Origin) {
+                $(if let Some(value) = file.$field {
+                    self.$field = Setting { value, origin: origin.clone() };
+                })*
+            }
+
+            fn apply_env(&mut self) -> Result<(), String> {
+                $(
+                    let name = format!("{}{}", ENV_PREFIX, stringify!($field).to_ascii_uppercase());
+                    if let Some(raw) = env::var_os(&name) {
+                        let value = raw
+                            .to_str()
+                            .and_then(<$type>::from_env)
+                            .ok_or_else(|| format!("Invalid value for {}: {}", name, raw.to_string_lossy()))?;
+                        self.$field = Setting { value, origin: Origin::Environment(name) };
+                    }
+                )*
+                Ok(())
+            }
+
+            // `(key, value as TOML, origin)` for every setting, in declaration order
+            pub fn entries(&self) -> Vec<(&'static str, String, &Origin)> {
+                vec![$((
+                    stringify!($field),
+                    toml::Value::try_from(&self.$field.value).map(|value| value.to_string()).unwrap_or_default(),
+                    &self.$field.origin,
+                ),)*]
+            }
+        }
+    };
+}
+
+settings! {
+    mode: String = "encrypt".to_string(),
+    extension: String = "lockit".to_string(),
+    directory_extension: String = "dir".to_string(),
+    encrypt_filenames: bool = true,
+    self_destruct: bool = false,
+    skip_dod: bool = false,
+    cipher: String = "aes-256-gcm".to_string(),
+    on_conflict: String = "fail".to_string(),
+    preserve_owner: bool = false,
+    preserve_xattrs: bool = false,
+    argon2_memory_kib: u32 = 65536,
+    argon2_iterations: u32 = 3,
+    argon2_parallelism: u32 = 4,
+}
+
+// Layers, from lowest to highest precedence: built-in defaults, the system file, the user file,
+// the nearest `.lockit.toml` and `LOCKIT_*` variables. Command line flags are applied by the caller.
+pub fn load() -> Result<Settings, String> {
+    let mut settings = Settings::built_in();
+    for path in config_files() {
+        if !path.is_file() {
+            continue;
+        }
+        let contents = fs::read_to_string(&path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+        let file: FileSettings = toml::from_str(&contents).map_err(|e| format!("Invalid configuration in {}: {}", path.display(), e))?;
+        settings.apply_file(file, &Origin::File(path));
+    }
+    settings.apply_env()?;
+    Ok(settings)
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.mode.value != "encrypt" && self.mode.value != "decrypt" {
+            return Err(format!("Invalid mode '{}' from {}, expected 'encrypt' or 'decrypt'", self.mode.value, self.mode.origin));
+        }
+
+        if Cipher::from_name(&self.cipher.value).is_none() {
+            return Err(format!(
+                "Unknown cipher '{}' from {}, expected '{}' or '{}'",
+                self.cipher.value,
+                self.cipher.origin,
+                Cipher::Aes256Gcm.name(),
+                Cipher::XChaCha20Poly1305.name()
+            ));
+        }
+
+        if ConflictPolicy::from_name(&self.on_conflict.value).is_none() {
+            return Err(format!(
+                "Invalid conflict policy '{}' from {}, expected 'fail', 'skip', 'rename' or 'overwrite'",
+                self.on_conflict.value, self.on_conflict.origin
+            ));
+        }
+
+        for setting in [&self.extension, &self.directory_extension] {
+            let value = &setting.value;
+            if value.is_empty() || value.contains(['.', '/', '\\']) {
+                return Err(format!("Invalid extension '{}' from {}", value, setting.origin));
+            }
+        }
+
+        for setting in [&self.argon2_memory_kib, &self.argon2_iterations, &self.argon2_parallelism] {
+            if setting.value == 0 {
+                return Err(format!("Argon2id parameters from {} must not be zero", setting.origin));
+            }
+        }
+        crypto::argon2_params(self.argon2_memory_kib.value, self.argon2_iterations.value, self.argon2_parallelism.value).map_err(|e| {
+            format!(
+                "{} (memory from {}, iterations from {}, parallelism from {})",
+                e, self.argon2_memory_kib.origin, self.argon2_iterations.origin, self.argon2_parallelism.origin
+            )
+        })?;
+        Ok(())
+    }
+}
+
+// What to do when an output path already exists
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConflictPolicy {
+    Fail,
+    Skip,
+    Rename,
+    Overwrite,
+}
+
+impl ConflictPolicy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "fail" => Some(ConflictPolicy::Fail),
+            "skip" => Some(ConflictPolicy::Skip),
+            "rename" => Some(ConflictPolicy::Rename),
+            "overwrite" => Some(ConflictPolicy::Overwrite),
+            _ => None,
+        }
+    }
+}
+
+pub fn init(settings: Settings) {
+    let _ = SETTINGS.set(settings);
+}
+
+pub fn get() -> &'static Settings {
+    SETTINGS.get_or_init(Settings::built_in)
+}
+
+fn config_files() -> Vec<PathBuf> {
+    let mut files = Vec::new();
+
+    if cfg!(windows) {
+        files.extend(env::var_os("PROGRAMDATA").map(|dir| Path::new(&dir).join("lockit").join("config.toml")));
+        files.extend(env::var_os("APPDATA").map(|dir| Path::new(&dir).join("lockit").join("config.toml")));
+    } else {
+        files.push(PathBuf::from("/etc/lockit/config.toml"));
+        let user_dir = env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+        files.extend(user_dir.map(|dir| dir.join("lockit").join("config.toml")));
+    }
+
+    files.extend(project_config_file());
+    files
+}
+
+// The nearest `.lockit.toml` in the current directory or one of its parents
+fn project_config_file() -> Option<PathBuf> {
+    let current_dir = env::current_dir().ok()?;
+    current_dir
+        .ancestors()
+        .map(|dir| dir.join(PROJECT_CONFIG_FILE))
+        .find(|path| path.is_file())
+}
@@ -1,49 +1,667 @@
 use aes_gcm::aead::{AeadInPlace, KeyInit, OsRng};
 use aes_gcm::{Aes256Gcm, Key, Nonce};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
 use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
 use rand::RngCore;
 use sha2::Sha256;
-use std::io;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read, Write};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::config;
+use crate::error::{LockitError, Result};
+use crate::header::{self, Cipher, Compression, Header, Kdf};
+use crate::keyfile;
+use crate::recipient;
 
 const KEY_SIZE: usize = 32;
 const NONCE_SIZE: usize = 12;
+const SALT_SIZE: usize = 16;
+const ARGON2_PARAMS_SIZE: usize = SALT_SIZE + 12;
+const TAG_SIZE: usize = 16;
+
+// Chunk nonces are `prefix || counter (u32 BE) || last-chunk flag`
+// Stream nonces end with a u32 chunk counter and the last-chunk flag
+const STREAM_NONCE_SUFFIX_SIZE: usize = 5;
+pub const CHUNK_SIZE: usize = 64 * 1024;
+const MAX_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+// Argon2id costs accepted from file headers: at most 4 GiB of memory, and enough passes and lanes
+// for any sane setting without letting a crafted header stall decryption
+const MAX_ARGON2_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+const MAX_ARGON2_ITERATIONS: u32 = 64;
+const MAX_ARGON2_PARALLELISM: u32 = 64;
+
+const CONTENT_KEY_INFO: &[u8] = b"lockit content key";
+const KEY_COMMITMENT_INFO: &[u8] = b"lockit key commitment";
+const KEY_WRAP_INFO: &[u8] = b"lockit key wrap";
+const WRAPPED_DATA_KEY_SIZE: usize = NONCE_SIZE + KEY_SIZE + TAG_SIZE;
+
+// Holds the key material for a run and caches the expensive master keys, so a directory tree
+// encrypted in one run only pays for a single Argon2id derivation or recipient key wrap.
+pub struct Keyring {
+    passphrase: Option<String>,
+    kdf_params: Vec<u8>,
+    recipient_stanzas: Vec<Vec<u8>>,
+    identities: Vec<StaticSecret>,
+    symmetric_key: Option<[u8; KEY_SIZE]>,
+    cipher: Cipher,
+    master_keys: RefCell<HashMap<Vec<u8>, [u8; KEY_SIZE]>>,
+}
+
+impl Keyring {
+    // Runs that only use recipients or identities have no passphrase
+    pub fn new(passphrase: Option<String>) -> Self {
+        let settings = config::get();
+        let mut kdf_params = generate_random_bytes(SALT_SIZE);
+        kdf_params.extend_from_slice(&settings.argon2_memory_kib.value.to_le_bytes());
+        kdf_params.extend_from_slice(&settings.argon2_iterations.value.to_le_bytes());
+        kdf_params.extend_from_slice(&settings.argon2_parallelism.value.to_le_bytes());
+
+        Keyring {
+            passphrase,
+            kdf_params,
+            recipient_stanzas: Vec::new(),
+            identities: Vec::new(),
+            symmetric_key: None,
+            cipher: Cipher::from_name(&settings.cipher.value).unwrap_or(Cipher::Aes256Gcm),
+            master_keys: RefCell::new(HashMap::new()),
+        }
+    }
+
+    // Encrypts to the given public keys instead of the passphrase. A random file key is wrapped
+    // once for every recipient and shared by everything written in this run.
+    pub fn with_recipients(mut self, recipients: &[PublicKey]) -> Result<Self> {
+        let mut file_key = [0u8; KEY_SIZE];
+        OsRng.fill_bytes(&mut file_key);
+
+        self.recipient_stanzas = recipients
+            .iter()
+            .map(|recipient| recipient::wrap_file_key(&file_key, recipient))
+            .collect::<io::Result<_>>()?;
+        self.master_keys.borrow_mut().insert(stanzas_cache_key(&self.recipient_stanzas), file_key);
+        Ok(self)
+    }
+
+    pub fn with_identities(mut self, identities: Vec<StaticSecret>) -> Self {
+        self.identities = identities;
+        self
+    }
+
+    // Encrypts with a random 256-bit key from a key file, which is used as the master key as is
+    pub fn with_key(mut self, key: [u8; KEY_SIZE]) -> Self {
+        self.symmetric_key = Some(key);
+        self
+    }
+
+    fn new_header(&self, compression: Compression, flags: u8, nonce: Vec<u8>, mut extensions: Vec<(u8, Vec<u8>)>) -> Header {
+        let (kdf, kdf_params) = if !self.recipient_stanzas.is_empty() {
+            for stanza in &self.recipient_stanzas {
+                extensions.push((header::EXT_RECIPIENT, stanza.clone()));
+            }
+            (Kdf::X25519, Vec::new())
+        } else if let Some(key) = &self.symmetric_key {
+            (Kdf::RawKey, keyfile::key_id(key).to_vec())
+        } else {
+            (Kdf::Argon2id, self.kdf_params.clone())
+        };
+
+        Header {
+            version: header::FORMAT_VERSION,
+            cipher: self.cipher,
+            kdf,
+            kdf_params,
+            compression,
+            flags,
+            nonce,
+            extensions,
+        }
+    }
+
+    fn master_key(&self, header: &Header) -> Result<[u8; KEY_SIZE]> {
+        let stanzas = header.recipient_stanzas();
+        let cache_key = match header.kdf {
+            Kdf::X25519 => stanzas_cache_key(&stanzas),
+            _ => [&[header.kdf.id()], header.kdf_params.as_slice()].concat(),
+        };
+
+        if let Some(key) = self.master_keys.borrow().get(&cache_key) {
+            return Ok(*key);
+        }
+
+        let key = match header.kdf {
+            Kdf::X25519 => self.unwrap_file_key(&stanzas)?,
+            Kdf::RawKey => self.raw_key(&header.kdf_params)?,
+            _ => derive_argon2_key(self.passphrase()?, &header.kdf_params)?,
+        };
+        self.master_keys.borrow_mut().insert(cache_key, key);
+        Ok(key)
+    }
+
+    fn unwrap_file_key(&self, stanzas: &[&[u8]]) -> Result<[u8; KEY_SIZE]> {
+        if self.identities.is_empty() {
+            return Err(LockitError::WrongKey("File is encrypted to recipients, an --identity is required".into()));
+        }
+
+        self.identities
+            .iter()
+            .flat_map(|identity| stanzas.iter().map(move |stanza| (identity, stanza)))
+            .find_map(|(identity, stanza)| recipient::unwrap_file_key(stanza, identity))
+            .ok_or_else(|| LockitError::WrongKey("None of the identities can decrypt this file".into()))
+    }
+
+    // The key id in the header tells a wrong key file apart from a damaged file
+    fn raw_key(&self, key_id: &[u8]) -> Result<[u8; KEY_SIZE]> {
+        let key = self
+            .symmetric_key
+            .ok_or_else(|| LockitError::WrongKey("File is encrypted with a key file, --key-file is required".into()))?;
+
+        let expected = keyfile::key_id(&key);
+        if key_id != expected {
+            return Err(LockitError::WrongKey(format!("Wrong key file: file was encrypted with key id {}, the key file has key id {}", hex::encode(key_id), hex::encode(expected)),
+            ));
+        }
+        Ok(key)
+    }
+
+    fn passphrase(&self) -> Result<&str> {
+        self.passphrase
+            .as_deref()
+            .ok_or_else(|| LockitError::WrongKey("File is encrypted with a passphrase, but none was given".into()))
+    }
+
+    // Whether `header` was written under this keyring's own salt, key file or recipient stanzas
+    pub fn is_own_header(&self, header: &Header) -> bool {
+        let own = self.new_header(header.compression, header.flags, Vec::new(), Vec::new());
+        own.kdf == header.kdf && own.kdf_params == header.kdf_params && own.recipient_stanzas() == header.recipient_stanzas()
+    }
+
+    fn key_encryption_key(&self, header: &Header) -> Result<Aes256Gcm> {
+        let master_key = self.master_key(header)?;
+        let hkdf = Hkdf::<Sha256>::new(None, &master_key);
+        let mut key = [0u8; KEY_SIZE];
+        hkdf.expand(KEY_WRAP_INFO, &mut key).unwrap();
+        Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)))
+    }
+
+    fn seal_data_key(&self, header: &mut Header, data_key: &[u8; KEY_SIZE]) -> Result<()> {
+        let nonce = generate_random_bytes(NONCE_SIZE);
+        let mut wrapped = data_key.to_vec();
+        self.key_encryption_key(header)?
+            .encrypt_in_place(Nonce::from_slice(&nonce), b"", &mut wrapped)
+            .map_err(|_| io::Error::other("Failed to wrap data key"))?;
+
+        header.extensions.push((header::EXT_WRAPPED_KEY, [nonce, wrapped].concat()));
+        Ok(())
+    }
+
+    // Files written before envelope encryption use the master key as their data key
+    fn data_key(&self, header: &Header) -> Result<[u8; KEY_SIZE]> {
+        let wrapped = match header.extension(header::EXT_WRAPPED_KEY) {
+            Some(wrapped) => wrapped,
+            None => return self.master_key(header),
+        };
+        if wrapped.len() != WRAPPED_DATA_KEY_SIZE {
+            return Err(LockitError::Corrupt("Invalid wrapped data key".into()));
+        }
+
+        let (nonce, sealed) = wrapped.split_at(NONCE_SIZE);
+        let mut data_key = sealed.to_vec();
+        self.key_encryption_key(header)?
+            .decrypt_in_place(Nonce::from_slice(nonce), b"", &mut data_key)
+            .map_err(|_| LockitError::WrongKey("Wrong key, cannot unwrap the data key".into()))?;
+        Ok(data_key.try_into().unwrap())
+    }
+
+    fn content_key(&self, header: &Header) -> Result<[u8; KEY_SIZE]> {
+        match header.kdf {
+            // Only headerless files use it, which are decrypted by `decrypt_legacy`
+            Kdf::HkdfSha256 => Err(LockitError::Unsupported("KDF id 1 is not valid in a versioned header".into())),
+            Kdf::Argon2id | Kdf::X25519 | Kdf::RawKey => {
+                // The data key is only used to derive per-file sub-keys, salted with the file nonce
+                let data_key = self.data_key(header)?;
+                let hkdf = Hkdf::<Sha256>::new(Some(&header.nonce), &data_key);
+                let mut key = [0u8; KEY_SIZE];
+                hkdf.expand(CONTENT_KEY_INFO, &mut key).unwrap();
+                Ok(key)
+            }
+        }
+    }
+
+    // Must be called once the header carries its final nonce and key records
+    fn commit_key(&self, header: &mut Header) -> Result<()> {
+        let commitment = key_commitment(&self.content_key(header)?).finalize().into_bytes();
+        header.extensions.push((header::EXT_KEY_COMMITMENT, commitment.to_vec()));
+        Ok(())
+    }
+
+    // AES-GCM and ChaCha20-Poly1305 are not key-committing: a crafted payload can authenticate under
+    // several keys. Checking the commitment first leaves exactly one key that opens the file.
+    fn content_cipher(&self, header: &Header) -> Result<ContentCipher> {
+        let key = self.content_key(header)?;
+        let commitment = header
+            .extension(header::EXT_KEY_COMMITMENT)
+            .ok_or_else(|| LockitError::Corrupt("File has no key commitment".into()))?;
+        key_commitment(&key).verify_slice(commitment).map_err(|_| {
+            LockitError::WrongKey("Key commitment does not match, the file was not encrypted with this key".into())
+        })?;
+        Ok(ContentCipher::new(header.cipher, &key))
+    }
+}
+
+fn key_commitment(key: &[u8; KEY_SIZE]) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).unwrap();
+    mac.update(KEY_COMMITMENT_INFO);
+    mac
+}
 
-fn derive_key(password: &str, salt: &[u8]) -> Key<Aes256Gcm> {
+// The payload AEAD named in the header. Both ciphers take 32-byte keys and add 16-byte tags, so
+// they only differ in their nonce size.
+enum ContentCipher {
+    Aes256Gcm(Box<Aes256Gcm>),
+    XChaCha20Poly1305(XChaCha20Poly1305),
+}
+
+impl ContentCipher {
+    fn new(cipher: Cipher, key: &[u8; KEY_SIZE]) -> Self {
+        match cipher {
+            Cipher::Aes256Gcm => ContentCipher::Aes256Gcm(Box::new(Aes256Gcm::new(key.into()))),
+            Cipher::XChaCha20Poly1305 => ContentCipher::XChaCha20Poly1305(XChaCha20Poly1305::new(key.into())),
+        }
+    }
+
+    fn seal(&self, nonce: &[u8], aad: &[u8], buffer: &mut Vec<u8>) -> Result<()> {
+        match self {
+            ContentCipher::Aes256Gcm(cipher) => cipher.encrypt_in_place(Nonce::from_slice(nonce), aad, buffer),
+            ContentCipher::XChaCha20Poly1305(cipher) => cipher.encrypt_in_place(XNonce::from_slice(nonce), aad, buffer),
+        }
+        .map_err(|_| LockitError::Io(io::Error::other("Encryption failed")))
+    }
+
+    fn open(&self, nonce: &[u8], aad: &[u8], buffer: &mut Vec<u8>) -> Result<()> {
+        match self {
+            ContentCipher::Aes256Gcm(cipher) => cipher.decrypt_in_place(Nonce::from_slice(nonce), aad, buffer),
+            ContentCipher::XChaCha20Poly1305(cipher) => cipher.decrypt_in_place(XNonce::from_slice(nonce), aad, buffer),
+        }
+        .map_err(|_| LockitError::Corrupt("Decryption failed, the data is damaged or was modified".into()))
+    }
+}
+
+// Re-wraps the data key of an enveloped header under `new`, leaving everything the payload is
+// authenticated against untouched.
+pub fn rekey_header(header: &Header, old: &Keyring, new: &Keyring) -> Result<Header> {
+    if header.extension(header::EXT_WRAPPED_KEY).is_none() {
+        return Err(LockitError::Unsupported("File predates envelope encryption, decrypt and encrypt it again instead".into()));
+    }
+
+    let data_key = old.data_key(header)?;
+    let extensions = header
+        .extensions
+        .iter()
+        .filter(|(tag, _)| !Header::is_key_extension(*tag))
+        .cloned()
+        .collect();
+
+    let mut rekeyed = new.new_header(header.compression, header.flags, header.nonce.clone(), extensions);
+    rekeyed.version = header.version;
+    rekeyed.cipher = header.cipher;
+    new.seal_data_key(&mut rekeyed, &data_key)?;
+    Ok(rekeyed)
+}
+
+fn stanzas_cache_key<T: AsRef<[u8]>>(stanzas: &[T]) -> Vec<u8> {
+    let mut key = vec![Kdf::X25519.id()];
+    for stanza in stanzas {
+        key.extend_from_slice(stanza.as_ref());
+    }
+    key
+}
+
+fn derive_legacy_key(password: &str, salt: &[u8]) -> [u8; KEY_SIZE] {
     let hkdf = Hkdf::<Sha256>::new(Some(salt), password.as_bytes());
     let mut key = [0u8; KEY_SIZE];
     hkdf.expand(&[], &mut key).unwrap();
-    *Key::<Aes256Gcm>::from_slice(&key)
+    key
+}
+
+// Memory in KiB, iterations and parallelism, stored after the salt
+pub fn argon2_costs(kdf_params: &[u8]) -> Option<(u32, u32, u32)> {
+    let costs = kdf_params.get(SALT_SIZE..).filter(|costs| costs.len() == ARGON2_PARAMS_SIZE - SALT_SIZE)?;
+    let cost = |i: usize| u32::from_le_bytes([costs[i], costs[i + 1], costs[i + 2], costs[i + 3]]);
+    Some((cost(0), cost(4), cost(8)))
+}
+
+// Costs this reader accepts. Writing is held to the same limits, so every file it writes can be read back.
+pub fn argon2_params(memory_kib: u32, iterations: u32, parallelism: u32) -> std::result::Result<Params, String> {
+    if memory_kib > MAX_ARGON2_MEMORY_KIB || iterations > MAX_ARGON2_ITERATIONS || parallelism > MAX_ARGON2_PARALLELISM {
+        return Err(format!(
+            "Argon2id parameters exceed supported limits: at most {} KiB memory, {} iterations and parallelism {}",
+            MAX_ARGON2_MEMORY_KIB, MAX_ARGON2_ITERATIONS, MAX_ARGON2_PARALLELISM
+        ));
+    }
+    Params::new(memory_kib, iterations, parallelism, Some(KEY_SIZE)).map_err(|e| format!("Invalid Argon2id parameters: {}", e))
+}
+
+fn derive_argon2_key(password: &str, kdf_params: &[u8]) -> Result<[u8; KEY_SIZE]> {
+    if kdf_params.len() != ARGON2_PARAMS_SIZE {
+        return Err(LockitError::Corrupt("Invalid Argon2id parameters".into()));
+    }
+
+    let (salt, _) = kdf_params.split_at(SALT_SIZE);
+    let (memory_kib, iterations, parallelism) = argon2_costs(kdf_params).unwrap();
+
+    let params = argon2_params(memory_kib, iterations, parallelism).map_err(LockitError::Corrupt)?;
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+
+    let mut key = [0u8; KEY_SIZE];
+    argon2
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|e| io::Error::other(format!("Key derivation failed: {}", e)))?;
+    Ok(key)
 }
 
-pub fn encrypt_data(data: &[u8], password: &str) -> io::Result<Vec<u8>> {
-    let salt = generate_random_bytes(16);
-    let nonce = generate_random_bytes(NONCE_SIZE);
-    let key = derive_key(password, &salt);
-    let cipher = Aes256Gcm::new(&key);
+pub fn encrypt_data(data: &[u8], keyring: &Keyring, compression: Compression, flags: u8) -> Result<Vec<u8>> {
+    let mut header = keyring.new_header(compression, flags, generate_random_bytes(keyring.cipher.nonce_size()), Vec::new());
+    keyring.commit_key(&mut header)?;
 
+    let cipher = keyring.content_cipher(&header)?;
+    let mut result = header.encode();
+
+    // The encoded header is authenticated as associated data
     let mut buffer = data.to_vec();
-        cipher
-        .encrypt_in_place(Nonce::from_slice(&nonce), b"", &mut buffer)
-        .map_err(|_| io::Error::new(io::ErrorKind::Other, "Encryption failed"))?;
+    cipher.seal(&header.nonce, &result, &mut buffer)?;
 
-    let mut result = Vec::new();
-    result.extend_from_slice(&salt);
-    result.extend_from_slice(&nonce);
     result.extend_from_slice(&buffer);
     Ok(result)
 }
 
-pub fn decrypt_data(data: &[u8], password: &str) -> io::Result<Vec<u8>> {
-    let (salt, rest) = data.split_at(16);
+pub fn decrypt_data(data: &[u8], keyring: &Keyring) -> Result<(Header, Vec<u8>)> {
+    if !header::has_magic(data) {
+        return decrypt_legacy(data, keyring);
+    }
+
+    let (header, header_len) = header::parse(data)?;
+    if header.nonce.len() != header.cipher.nonce_size() {
+        return Err(LockitError::Corrupt("Invalid nonce length".into()));
+    }
+
+    let (encoded, enc_data) = data.split_at(header_len);
+    let aad = header.associated_data(encoded);
+    let cipher = keyring.content_cipher(&header)?;
+
+    let mut buffer = enc_data.to_vec();
+    cipher.open(&header.nonce, &aad, &mut buffer)?;
+    Ok((header, buffer))
+}
+
+// Headerless `salt || nonce || ciphertext` files written before format version 1.
+// Their payloads were always zstd-compressed, except for encrypted filenames.
+fn decrypt_legacy(data: &[u8], keyring: &Keyring) -> Result<(Header, Vec<u8>)> {
+    if data.len() < SALT_SIZE + NONCE_SIZE + TAG_SIZE {
+        return Err(LockitError::Corrupt("Input is too short to be encrypted data".into()));
+    }
+
+    let (salt, rest) = data.split_at(SALT_SIZE);
     let (nonce, enc_data) = rest.split_at(NONCE_SIZE);
-    let key = derive_key(password, salt);
-    let cipher = Aes256Gcm::new(&key);
+    let cipher = ContentCipher::new(Cipher::Aes256Gcm, &derive_legacy_key(keyring.passphrase()?, salt));
 
+    // Without a key commitment, a wrong passphrase and a damaged file look the same
     let mut buffer = enc_data.to_vec();
     cipher
-        .decrypt_in_place(Nonce::from_slice(nonce), b"", &mut buffer)
-        .map_err(|_| io::Error::new(io::ErrorKind::Other, "Decryption failed"))?;
-    Ok(buffer)
+        .open(nonce, b"", &mut buffer)
+        .map_err(|_| LockitError::WrongKey("Decryption failed, wrong passphrase or damaged file".into()))?;
+
+    let header = Header {
+        version: header::LEGACY_VERSION,
+        cipher: Cipher::Aes256Gcm,
+        kdf: Kdf::HkdfSha256,
+        kdf_params: salt.to_vec(),
+        compression: Compression::Zstd,
+        flags: 0,
+        nonce: nonce.to_vec(),
+        extensions: Vec::new(),
+    };
+    Ok((header, buffer))
+}
+
+pub struct ChunkCipher {
+    cipher: ContentCipher,
+    nonce_prefix: Vec<u8>,
+    aad: Vec<u8>,
+    counter: u32,
+}
+
+impl ChunkCipher {
+    fn new(keyring: &Keyring, header: &Header, encoded: &[u8]) -> Result<Self> {
+        if header.nonce.len() != stream_nonce_prefix_size(header.cipher) {
+            return Err(LockitError::Corrupt("Invalid stream nonce prefix length".into()));
+        }
+
+        Ok(ChunkCipher {
+            cipher: keyring.content_cipher(header)?,
+            nonce_prefix: header.nonce.clone(),
+            aad: header.associated_data(encoded),
+            counter: 0,
+        })
+    }
+
+    fn next_nonce(&mut self, last: bool) -> Result<Vec<u8>> {
+        let mut nonce = Vec::with_capacity(self.nonce_prefix.len() + STREAM_NONCE_SUFFIX_SIZE);
+        nonce.extend_from_slice(&self.nonce_prefix);
+        nonce.extend_from_slice(&self.counter.to_be_bytes());
+        nonce.push(last as u8);
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| io::Error::other("Too many chunks in encrypted stream"))?;
+        Ok(nonce)
+    }
+
+    pub fn seal(&mut self, buffer: &mut Vec<u8>, last: bool) -> Result<()> {
+        let nonce = self.next_nonce(last)?;
+        self.cipher.seal(&nonce, &self.aad, buffer)
+    }
+
+    pub fn open(&mut self, buffer: &mut Vec<u8>, last: bool) -> Result<()> {
+        let nonce = self.next_nonce(last)?;
+        self.cipher.open(&nonce, &self.aad, buffer)
+    }
+}
+
+// Writes a header followed by fixed-size sealed chunks. Full chunks are sealed as soon as they
+// fill up, so the final chunk is always shorter than CHUNK_SIZE (possibly empty) and carries the
+// last-chunk flag; a stream cut at a chunk boundary is therefore detected as truncated.
+pub struct StreamEncryptor<W: Write> {
+    inner: W,
+    chunks: ChunkCipher,
+    buffer: Vec<u8>,
+}
+
+fn stream_nonce_prefix_size(cipher: Cipher) -> usize {
+    cipher.nonce_size() - STREAM_NONCE_SUFFIX_SIZE
+}
+
+// Writes a stream header carrying the given extension records and returns the cipher for its chunks
+pub fn start_stream<W: Write>(
+    writer: &mut W,
+    keyring: &Keyring,
+    compression: Compression,
+    flags: u8,
+    extensions: Vec<(u8, Vec<u8>)>,
+) -> Result<ChunkCipher> {
+    let mut header = keyring.new_header(compression, flags, generate_random_bytes(stream_nonce_prefix_size(keyring.cipher)), extensions);
+    let mut data_key = [0u8; KEY_SIZE];
+    OsRng.fill_bytes(&mut data_key);
+    keyring.seal_data_key(&mut header, &data_key)?;
+    keyring.commit_key(&mut header)?;
+
+    let encoded = header.encode();
+    let chunks = ChunkCipher::new(keyring, &header, &encoded)?;
+    writer.write_all(&encoded)?;
+    Ok(chunks)
+}
+
+// Reads a stream header written by `start_stream` that carries the given extension record
+pub fn open_stream<R: Read>(reader: &mut R, keyring: &Keyring, tag: u8) -> Result<(Header, ChunkCipher)> {
+    match read_header(reader)? {
+        (data, Some(header)) if header.extension(tag).is_some() => {
+            let chunks = ChunkCipher::new(keyring, &header, &data)?;
+            Ok((header, chunks))
+        }
+        _ => Err(LockitError::Corrupt("Input is not a lockit stream of the expected kind".into())),
+    }
+}
+
+impl<W: Write> StreamEncryptor<W> {
+    // `extensions` are written after the chunk size record
+    pub fn new(mut inner: W, keyring: &Keyring, compression: Compression, flags: u8, extensions: Vec<(u8, Vec<u8>)>) -> Result<Self> {
+        let mut all_extensions = vec![(header::EXT_CHUNK_SIZE, (CHUNK_SIZE as u32).to_le_bytes().to_vec())];
+        all_extensions.extend(extensions);
+        let chunks = start_stream(&mut inner, keyring, compression, flags, all_extensions)?;
+
+        Ok(StreamEncryptor {
+            inner,
+            chunks,
+            buffer: Vec::with_capacity(CHUNK_SIZE + TAG_SIZE),
+        })
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        self.chunks.seal(&mut self.buffer, true)?;
+        self.inner.write_all(&self.buffer)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for StreamEncryptor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+
+        if self.buffer.len() == CHUNK_SIZE {
+            self.chunks.seal(&mut self.buffer, false)?;
+            self.inner.write_all(&self.buffer)?;
+            self.buffer.clear();
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct StreamDecryptor<R: Read> {
+    inner: R,
+    chunks: ChunkCipher,
+    chunk_size: usize,
+    buffer: Vec<u8>,
+    pos: usize,
+    finished: bool,
+}
+
+impl<R: Read> StreamDecryptor<R> {
+    fn fill_buffer(&mut self) -> io::Result<()> {
+        self.buffer.resize(self.chunk_size + TAG_SIZE, 0);
+        let len = read_full(&mut self.inner, &mut self.buffer)?;
+        self.buffer.truncate(len);
+        self.pos = 0;
+
+        if len < TAG_SIZE {
+            return Err(LockitError::Corrupt("Encrypted stream is truncated".into()).into());
+        }
+
+        // Only the final chunk may be shorter than a full chunk
+        let last = len < self.chunk_size + TAG_SIZE;
+        self.chunks.open(&mut self.buffer, last)?;
+        self.finished = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for StreamDecryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buffer.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.fill_buffer()?;
+        }
+
+        let len = buf.len().min(self.buffer.len() - self.pos);
+        buf[..len].copy_from_slice(&self.buffer[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+// Opens any supported ciphertext for reading: chunked streams are decrypted incrementally, while
+// single-message and legacy files are authenticated in full before any plaintext is returned.
+pub fn decrypt_reader<R: Read + 'static>(mut reader: R, keyring: &Keyring) -> Result<(Header, Box<dyn Read>)> {
+    let (mut data, header) = read_header(&mut reader)?;
+
+    if let Some(header) = header {
+        if let Some(chunk_size) = header.chunk_size() {
+            let chunk_size = chunk_size as usize;
+            if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+                return Err(LockitError::Corrupt("Invalid chunk size".into()));
+            }
+
+            let decryptor = StreamDecryptor {
+                inner: reader,
+                chunks: ChunkCipher::new(keyring, &header, &data)?,
+                chunk_size,
+                buffer: Vec::new(),
+                pos: 0,
+                finished: false,
+            };
+            return Ok((header, Box::new(decryptor)));
+        }
+    }
+
+    reader.read_to_end(&mut data)?;
+    let (header, plaintext) = decrypt_data(&data, keyring)?;
+    Ok((header, Box::new(Cursor::new(plaintext))))
+}
+
+// Reads a versioned header from the start of `reader`. Returns the consumed bytes, which are the
+// encoded header if one was found, or the first bytes of a legacy file otherwise.
+pub fn read_header<R: Read>(reader: &mut R) -> Result<(Vec<u8>, Option<Header>)> {
+    let mut data = vec![0u8; header::PREFIX_LEN];
+    let len = read_full(reader, &mut data)?;
+    data.truncate(len);
+
+    if len < header::PREFIX_LEN || !header::has_magic(&data) {
+        return Ok((data, None));
+    }
+
+    let header_len = header::encoded_len(&data);
+    if header_len > header::PREFIX_LEN {
+        data.resize(header_len, 0);
+        if read_full(reader, &mut data[header::PREFIX_LEN..])? != header_len - header::PREFIX_LEN {
+            return Err(LockitError::Corrupt("Truncated lockit header".into()));
+        }
+    }
+
+    let (header, _) = header::parse(&data)?;
+    Ok((data, Some(header)))
+}
+
+pub fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match reader.read(&mut buf[total..]) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(total)
 }
 
 fn generate_random_bytes(len: usize) -> Vec<u8> {
@@ -51,3 +669,173 @@ fn generate_random_bytes(len: usize) -> Vec<u8> {
     OsRng.fill_bytes(&mut bytes);
     bytes
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CIPHERS: [Cipher; 2] = [Cipher::Aes256Gcm, Cipher::XChaCha20Poly1305];
+
+    // Written with the key file key `[0x42; 32]`, to catch any drift of the format. Each one holds
+    // the plaintext `lockit known answer`, as a chunked stream and as a single message.
+    const KNOWN_ANSWERS: [(Cipher, &str, &str); 2] = [
+        (
+            Cipher::Aes256Gcm,
+            "4c4f434b49540287000104000008433be465bba0d3f407cb7f5aa7e3d0a081040000000100843c0076b1b0521eb34fd2f0a4336cc7c06181f6e46197e5cc97078ffda33f67cf8c3ba550f90e45a3047c0ed22a093c9aee02946b3475a5859e98413274ef852000eab3c12f9e4553141efde379c349203f225473b974e70aaaa609a7a1447bb8556f1ba43ce72ca6dfde83395c0260ffbf6fd9881dcd6f156c134d881c76ca879c55b3bc",
+            "4c4f434b49540246000104000008433be465bba0d3f40c3b47cec6b4dbe12620d479e48520002c3473967e95da0cfbc25d8d3240204be1f7562c894b2c03efd2144e0084d5b665db39fa20c950c382f7777edec103ec835b9b102b154890863c3b675f4afb22d8a560",
+        ),
+        (
+            Cipher::XChaCha20Poly1305,
+            "4c4f434b49540293000204000008433be465bba0d3f4130710d29fb7eb11ebe48914b0eea2373e00e9a681040000000100843c0079b3a9cfd98fda7ec14f70663bb2a0b35ea0ed6f6883d9645ff9682c6313654c5570004d14c0287717da300e77ea39fe978f0a5b60089c47b3339acf852000a6320397ce0f69baa14ef40176517953583ef1a330fc807f523f9229b56fc469e89fe3b746164bae16ed2a9401e0040d145228f6e09c7466187d69c009a0eee34e7f45",
+            "4c4f434b49540252000204000008433be465bba0d3f418ed6b83e5425a37ee5ea16d1e5cd9cc562762043ad9ce8218852000acb190d344f014cedd7726ee4c9e487b1d4548e5f5a940744d23b4e959cfa95ad8cf8beda84f710b4f824b49967d3b507a0c80fc4ecbd51602e2a57bcee8fa1c5bec42",
+        ),
+    ];
+
+    // A key file keyring skips Argon2id, which keeps the tests fast
+    fn keyring(cipher: Cipher) -> Keyring {
+        let mut keyring = Keyring::new(None).with_key([0x42; KEY_SIZE]);
+        keyring.cipher = cipher;
+        keyring
+    }
+
+    fn encrypt_stream(keyring: &Keyring, plaintext: &[u8]) -> Vec<u8> {
+        let mut encryptor = StreamEncryptor::new(Vec::new(), keyring, Compression::None, 0, Vec::new()).unwrap();
+        encryptor.write_all(plaintext).unwrap();
+        encryptor.finish().unwrap()
+    }
+
+    fn decrypt_stream(keyring: &Keyring, ciphertext: Vec<u8>) -> Result<(Header, Vec<u8>)> {
+        let (header, mut reader) = decrypt_reader(Cursor::new(ciphertext), keyring)?;
+        let mut plaintext = Vec::new();
+        reader.read_to_end(&mut plaintext)?;
+        Ok((header, plaintext))
+    }
+
+    #[test]
+    fn single_message_round_trip_with_both_ciphers() {
+        for cipher in CIPHERS {
+            let keyring = keyring(cipher);
+            let ciphertext = encrypt_data(b"single message", &keyring, Compression::None, 0).unwrap();
+            let (header, plaintext) = decrypt_data(&ciphertext, &keyring).unwrap();
+            assert_eq!(header.cipher, cipher);
+            assert_eq!(plaintext, b"single message");
+        }
+    }
+
+    #[test]
+    fn chunked_round_trip_with_both_ciphers() {
+        // Empty, shorter than a chunk, exactly one chunk and several chunks with a partial one
+        let sizes = [0, 100, CHUNK_SIZE, 2 * CHUNK_SIZE + CHUNK_SIZE / 2];
+        for cipher in CIPHERS {
+            let keyring = keyring(cipher);
+            for size in sizes {
+                let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+                let (header, plaintext) = decrypt_stream(&keyring, encrypt_stream(&keyring, &data)).unwrap();
+                assert_eq!(header.cipher, cipher);
+                assert_eq!(header.chunk_size(), Some(CHUNK_SIZE as u32));
+                assert_eq!(plaintext, data, "{:?} with {} bytes", cipher, size);
+            }
+        }
+    }
+
+    #[test]
+    fn known_answer_vectors_decrypt() {
+        for (cipher, stream, single) in KNOWN_ANSWERS {
+            let keyring = keyring(cipher);
+
+            let (header, plaintext) = decrypt_stream(&keyring, hex::decode(stream).unwrap()).unwrap();
+            assert_eq!((header.version, header.cipher, header.kdf), (2, cipher, Kdf::RawKey));
+            assert_eq!(plaintext, b"lockit known answer");
+
+            let (header, plaintext) = decrypt_data(&hex::decode(single).unwrap(), &keyring).unwrap();
+            assert_eq!((header.version, header.cipher, header.kdf), (2, cipher, Kdf::RawKey));
+            assert_eq!(plaintext, b"lockit known answer");
+        }
+    }
+
+    #[test]
+    fn truncated_streams_are_rejected() {
+        let keyring = keyring(Cipher::Aes256Gcm);
+        let data = vec![5u8; 2 * CHUNK_SIZE + 10];
+        let ciphertext = encrypt_stream(&keyring, &data);
+        let header_len = read_header(&mut Cursor::new(&ciphertext)).unwrap().0.len();
+        let sealed_chunk = CHUNK_SIZE + TAG_SIZE;
+
+        // Dropping the final chunk leaves a stream that ends after a full chunk
+        let cuts = [header_len + 2 * sealed_chunk, header_len + sealed_chunk + 100, ciphertext.len() - 1, header_len + 3];
+        for cut in cuts {
+            let result = decrypt_stream(&keyring, ciphertext[..cut].to_vec());
+            assert!(matches!(result, Err(LockitError::Corrupt(_))), "cut at {}", cut);
+        }
+    }
+
+    #[test]
+    fn reordered_chunks_are_rejected() {
+        let keyring = keyring(Cipher::XChaCha20Poly1305);
+        let mut ciphertext = encrypt_stream(&keyring, &vec![5u8; 2 * CHUNK_SIZE + 10]);
+        let header_len = read_header(&mut Cursor::new(&ciphertext)).unwrap().0.len();
+        let sealed_chunk = CHUNK_SIZE + TAG_SIZE;
+
+        let (first, second) = ciphertext[header_len..header_len + 2 * sealed_chunk].split_at_mut(sealed_chunk);
+        first.swap_with_slice(second);
+        assert!(matches!(decrypt_stream(&keyring, ciphertext), Err(LockitError::Corrupt(_))));
+    }
+
+    // Argon2id at its lowest costs, so a wrong passphrase is only caught by the key commitment
+    fn passphrase_keyring(passphrase: &str) -> Keyring {
+        let mut keyring = Keyring::new(Some(passphrase.to_string()));
+        keyring.kdf_params = [vec![1; SALT_SIZE], 8u32.to_le_bytes().to_vec(), 1u32.to_le_bytes().to_vec(), 1u32.to_le_bytes().to_vec()].concat();
+        keyring
+    }
+
+    fn with_commitment(ciphertext: &[u8], commitment: Option<Vec<u8>>) -> Vec<u8> {
+        let (mut header, len) = header::parse(ciphertext).unwrap();
+        header.extensions.retain(|(tag, _)| *tag != header::EXT_KEY_COMMITMENT);
+        if let Some(commitment) = commitment {
+            header.extensions.push((header::EXT_KEY_COMMITMENT, commitment));
+        }
+        [header.encode(), ciphertext[len..].to_vec()].concat()
+    }
+
+    #[test]
+    fn wrong_passphrase_fails_the_key_commitment() {
+        let ciphertext = encrypt_data(b"committed", &passphrase_keyring("right"), Compression::None, 0).unwrap();
+        let result = decrypt_data(&ciphertext, &passphrase_keyring("wrong"));
+        assert!(matches!(result, Err(LockitError::WrongKey(message)) if message.contains("Key commitment")));
+    }
+
+    #[test]
+    fn altered_or_missing_commitment_is_rejected() {
+        let keyring = keyring(Cipher::Aes256Gcm);
+        let ciphertext = encrypt_data(b"committed", &keyring, Compression::None, 0).unwrap();
+
+        let altered = with_commitment(&ciphertext, Some(vec![0; 32]));
+        assert!(matches!(decrypt_data(&altered, &keyring), Err(LockitError::WrongKey(_))));
+        let missing = with_commitment(&ciphertext, None);
+        assert!(matches!(decrypt_data(&missing, &keyring), Err(LockitError::Corrupt(_))));
+
+        let stream = encrypt_stream(&keyring, b"committed");
+        let missing = with_commitment(&stream, None);
+        assert!(matches!(decrypt_stream(&keyring, missing), Err(LockitError::Corrupt(_))));
+    }
+
+    #[test]
+    fn version_1_files_need_a_commitment_too() {
+        let keyring = keyring(Cipher::Aes256Gcm);
+        let mut header = keyring.new_header(Compression::None, 0, generate_random_bytes(NONCE_SIZE), Vec::new());
+        header.version = header::MIN_VERSION;
+        let mut payload = b"version 1".to_vec();
+        let key = keyring.content_key(&header).unwrap();
+        ContentCipher::new(header.cipher, &key).seal(&header.nonce, &header.encode(), &mut payload).unwrap();
+        let ciphertext = [header.encode(), payload].concat();
+
+        assert!(matches!(decrypt_data(&ciphertext, &keyring), Err(LockitError::Corrupt(_))));
+    }
+
+    #[test]
+    fn header_cipher_wins_over_the_keyring_cipher() {
+        let ciphertext = encrypt_stream(&keyring(Cipher::XChaCha20Poly1305), b"mixed");
+        let (_, plaintext) = decrypt_stream(&keyring(Cipher::Aes256Gcm), ciphertext).unwrap();
+        assert_eq!(plaintext, b"mixed");
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::plan::PlannedAction;
+
+pub type Result<T> = std::result::Result<T, LockitError>;
+
+// Every failure falls in one of these classes, and each class has its own exit code
+#[derive(Debug)]
+pub enum LockitError {
+    Usage(String),
+    WrongKey(String),
+    Corrupt(String),
+    Unsupported(String),
+    Io(io::Error),
+    PartialFailure { failed: usize, total: usize },
+}
+
+impl LockitError {
+    // Documented in the README; 0 is success
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            LockitError::Io(_) => 1,
+            LockitError::Usage(_) => 2,
+            LockitError::WrongKey(_) => 3,
+            LockitError::Corrupt(_) => 4,
+            LockitError::Unsupported(_) => 5,
+            LockitError::PartialFailure { .. } => 6,
+        }
+    }
+}
+
+impl fmt::Display for LockitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LockitError::Usage(message)
+            | LockitError::WrongKey(message)
+            | LockitError::Corrupt(message)
+            | LockitError::Unsupported(message) => write!(f, "{}", message),
+            LockitError::Io(e) => write!(f, "{}", e),
+            LockitError::PartialFailure { failed, total } => write!(f, "{} of {} paths failed", failed, total),
+        }
+    }
+}
+
+impl std::error::Error for LockitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LockitError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// Errors raised inside readers and writers travel wrapped in an `io::Error` and are unwrapped
+// again here. Other I/O errors are classified by kind, as the header parser and the key file
+// readers report malformed input as `InvalidData` and bad arguments as `InvalidInput`.
+impl From<io::Error> for LockitError {
+    fn from(e: io::Error) -> Self {
+        let e = match e.downcast::<LockitError>() {
+            Ok(inner) => return inner,
+            Err(e) => e,
+        };
+
+        match e.kind() {
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => LockitError::Corrupt(e.to_string()),
+            io::ErrorKind::Unsupported => LockitError::Unsupported(e.to_string()),
+            io::ErrorKind::InvalidInput => LockitError::Usage(e.to_string()),
+            _ => LockitError::Io(e),
+        }
+    }
+}
+
+impl From<LockitError> for io::Error {
+    fn from(e: LockitError) -> Self {
+        match e {
+            LockitError::Io(e) => e,
+            e => io::Error::other(e),
+        }
+    }
+}
+
+// Outcome of a run over several paths. Failures are printed as they happen and summarized at the end.
+#[derive(Default)]
+pub struct Report {
+    pub succeeded: usize,
+    pub skipped: Vec<(PathBuf, String)>,
+    pub failures: Vec<(PathBuf, LockitError)>,
+    // Filled instead of changing anything with --dry-run
+    pub planned: Vec<PlannedAction>,
+}
+
+impl Report {
+    pub fn record<T>(&mut self, path: &Path, result: Result<T>) {
+        match result {
+            Ok(_) => self.succeeded += 1,
+            Err(e) => self.fail(path, e),
+        }
+    }
+
+    pub fn skip(&mut self, path: &Path, reason: &str) {
+        eprintln!("Skipping {}: {}", path.display(), reason);
+        self.skipped.push((path.to_path_buf(), reason.to_string()));
+    }
+
+    pub fn plan(&mut self, action: PlannedAction) {
+        self.planned.push(action);
+    }
+
+    pub fn fail(&mut self, path: &Path, error: LockitError) {
+        eprintln!("Error: {}: {}", path.display(), error);
+        self.failures.push((path.to_path_buf(), error));
+    }
+
+    // A run where everything failed for the same reason exits with that reason's code. Mixed
+    // reasons, or failures next to successes, are a partial failure.
+    pub fn finish(mut self) -> Result<()> {
+        if !self.skipped.is_empty() {
+            eprintln!("{} paths skipped", self.skipped.len());
+        }
+
+        let failed = self.failures.len();
+        if failed == 0 {
+            return Ok(());
+        }
+
+        let total = self.succeeded + failed;
+        eprintln!("\n{} of {} paths failed:", failed, total);
+        for (path, error) in &self.failures {
+            eprintln!("    {}: {}", path.display(), error);
+        }
+
+        let first_code = self.failures[0].1.exit_code();
+        if self.succeeded == 0 && self.failures.iter().all(|(_, e)| e.exit_code() == first_code) {
+            return Err(self.failures.swap_remove(0).1);
+        }
+        Err(LockitError::PartialFailure { failed, total })
+    }
+}
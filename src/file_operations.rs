@@ -1,280 +1,1075 @@
-use std::fs::{self, OpenOptions};
-use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use std::fs::{self, File, OpenOptions};
+use std::ffi::{OsStr, OsString};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use tar::Archive;
-use hex;
-
-include!(concat!(env!("OUT_DIR"), "/default_settings.rs"));
-
-pub fn process_file_with_flags(
-    file_path: &Path, 
-    password: &str, 
-    encrypt: bool, 
-    encrypt_filenames: bool,
-    skip_dod: bool,
-) -> Option<PathBuf> {
-    let dir_lockit_extension = format!("{}.{}", CUSTOM_DIRECTORY_EXTENSION, CUSTOM_EXTENSION);
-
-    if !encrypt && file_path.file_name()?.to_str()?.ends_with(&dir_lockit_extension) {
-        return decrypt_and_extract_dir_lockit(file_path, password, encrypt_filenames, skip_dod);
-    }
-
-    match encrypt {
-        true => {
-            match compress_and_encrypt_file(file_path, password, encrypt_filenames, skip_dod) {
-                Some(path) => Some(path),
-                None => {
-                    eprintln!("Failed to encrypt file: {}", file_path.display());
-                    None
-                }
-            }
+
+use crate::crypto::{read_full, read_header, Keyring, StreamEncryptor};
+use crate::header::{self, Compression, EXT_RECORDS, FLAG_ARCHIVE, FLAG_ENCRYPTED_NAME, MAGIC};
+use crate::config::{self, ConflictPolicy};
+use crate::error::{LockitError, Report, Result};
+use crate::metadata::Metadata;
+use crate::names::{self, NameKey, LONG_NAME_EXTENSION, NAME_KEY_FILE};
+use crate::pipe;
+use crate::plan::PlannedAction;
+
+const WIPE_BUFFER_SIZE: usize = 1024 * 1024;
+
+// Stands in for encrypted names in the plan of a dry run when their directory has no name key yet
+const NEW_ENCRYPTED_NAME: &str = "<new encrypted name>";
+
+// How the paths of a run are processed, as given on the command line
+#[derive(Clone, Copy, Debug)]
+pub struct Flags<'a> {
+    pub encrypt: bool,
+    pub encrypt_filenames: bool,
+    pub dir_mode: bool,
+    pub skip_dod: bool,
+    pub force: bool,
+    pub keep: bool,
+    // Nothing is changed, every step is added to the plan of the report instead
+    pub dry_run: bool,
+    // Existing directory the outputs are written under, canonicalized, instead of next to their sources
+    pub output_dir: Option<&'a Path>,
+}
+
+impl Flags<'_> {
+    // Sources are renamed and wiped unless they are kept or the outputs go elsewhere
+    fn in_place(&self) -> bool {
+        !self.keep && self.output_dir.is_none()
+    }
+
+    // The directory that receives the output of a path given on the command line
+    fn target_dir<'p>(&'p self, path: &'p Path) -> &'p Path {
+        self.output_dir.unwrap_or_else(|| path.parent().unwrap_or(Path::new("")))
+    }
+}
+
+// Result of a single path that did not fail
+enum Outcome {
+    Done,
+    Skipped(String),
+    Planned(Vec<PlannedAction>),
+}
+
+pub fn process_file_with_flags(file_path: &Path, keyring: &Keyring, flags: &Flags, report: &mut Report) {
+    process_file(file_path, flags.target_dir(file_path), keyring, flags, report);
+}
+
+// Outputs are written into `target_dir`, which is the directory of the file when transforming in place
+fn process_file(file_path: &Path, target_dir: &Path, keyring: &Keyring, flags: &Flags, report: &mut Report) {
+    let dir_lockit_extension = format!("{}.{}", directory_extension(), extension());
+
+    let result = if flags.encrypt {
+        match already_encrypted(file_path) {
+            Ok(Some(reason)) if !flags.force => Ok(Outcome::Skipped(reason)),
+            Ok(_) => compress_and_encrypt_file(file_path, target_dir, keyring, flags),
+            Err(e) => Err(e),
         }
-        false => {
-            match decompress_and_decrypt_file(file_path, password, encrypt_filenames, skip_dod) {
-                Some(path) => Some(path),
-                None => {
-                    eprintln!("Failed to decrypt file: {}", file_path.display());
-                    None
-                }
-            }
+    } else if has_suffix(file_path, &dir_lockit_extension) {
+        decrypt_and_extract_dir_lockit(file_path, target_dir, keyring, flags)
+    } else if file_path.extension().and_then(|ext| ext.to_str()) != Some(extension()) {
+        Ok(Outcome::Skipped("unsupported extension".to_string()))
+    } else {
+        decompress_and_decrypt_file(file_path, target_dir, keyring, flags)
+    };
+
+    match result {
+        Ok(Outcome::Done) => report.succeeded += 1,
+        Ok(Outcome::Skipped(reason)) => report.skip(file_path, &reason),
+        Ok(Outcome::Planned(actions)) => {
+            actions.into_iter().for_each(|action| report.plan(action));
+            report.succeeded += 1;
         }
+        Err(e) => report.fail(file_path, e),
     }
 }
 
+// Encrypting again would only wrap the ciphertext in another layer. Files are recognized by their
+// header; older headerless files only by their extension.
+fn already_encrypted(file_path: &Path) -> Result<Option<String>> {
+    let mut magic = [0u8; MAGIC.len()];
+    let len = read_full(&mut File::open(file_path)?, &mut magic)?;
+    if header::has_magic(&magic[..len]) {
+        return Ok(Some("already encrypted by lockit, use --force to encrypt it again".to_string()));
+    }
+    if file_path.extension().and_then(|ext| ext.to_str()) == Some(extension()) {
+        return Ok(Some(format!("already has the .{} extension, use --force to encrypt it again", extension())));
+    }
+    Ok(None)
+}
 
-pub fn process_directory_with_flags(
-    directory_path: &Path,
-    password: &str,
-    encrypt: bool,
-    encrypt_filenames: bool,
-    dir_mode: bool,
-    skip_dod: bool,
-) -> Option<()> {
+// Directories renamed by an earlier encryption end with `.dir.lockit`
+fn is_encrypted_directory(path: &Path) -> bool {
+    has_suffix(path, &format!(".{}.{}", directory_extension(), extension()))
+}
+
+
+pub fn process_directory_with_flags(directory_path: &Path, keyring: &Keyring, flags: &Flags, report: &mut Report) {
+    process_directory_into(directory_path, flags.target_dir(directory_path), keyring, flags, report);
+}
+
+fn process_directory_into(directory_path: &Path, target_dir: &Path, keyring: &Keyring, flags: &Flags, report: &mut Report) {
+    if let Err(e) = process_directory(directory_path, target_dir, keyring, flags, report) {
+        report.fail(directory_path, e);
+    }
+}
+
+// Failures of single entries are recorded in `report`; an error is only returned when the
+// directory itself cannot be renamed, created, archived or listed. In place, the directory is
+// renamed and its entries are transformed where they are. Otherwise its counterpart is created in
+// `target_dir` and the entries are written into it.
+fn process_directory(directory_path: &Path, target_dir: &Path, keyring: &Keyring, flags: &Flags, report: &mut Report) -> Result<()> {
     let mut current_path = directory_path.to_path_buf();
-    let dir_lockit_extension = format!("{}.{}", CUSTOM_DIRECTORY_EXTENSION, CUSTOM_EXTENSION);
+    let dir_lockit_extension = format!("{}.{}", directory_extension(), extension());
+    let renamed_before = flags.encrypt && !flags.force && is_encrypted_directory(&current_path);
+    // Paths such as `.` have no name of their own to encrypt, only their contents are processed
+    let renamable = current_path.file_name().is_some();
+
+    if flags.dir_mode && flags.encrypt {
+        if renamed_before {
+            report.skip(directory_path, "already encrypted by lockit, use --force to archive it again");
+            return Ok(());
+        }
 
-    if encrypt {
-        if encrypt_filenames {
-            // Encrypt the directory name and add the .lockit extension
-            let encrypted_dir_name = get_new_filename(&current_path, password, true, encrypt_filenames)?;
-            let encrypted_path = current_path.with_file_name(format!("{}.{}", encrypted_dir_name, dir_lockit_extension));
+        let target = encrypted_path(&target_dir.join(file_name(&current_path)?), keyring, flags.encrypt_filenames, &format!(".{}", dir_lockit_extension), flags.dry_run)?;
+        let conflict = conflict(&target);
+        let Some(tar_filename) = resolve_output(&target)? else {
+            report.skip(directory_path, &already_exists(&target));
+            return Ok(());
+        };
 
-            if let Err(e) = fs::rename(&current_path, &encrypted_path) {
-                eprintln!("Failed to rename directory {}: {}", current_path.display(), e);
-                return None;
+        if flags.dry_run {
+            let size = tree_size(&current_path)?;
+            report.plan(PlannedAction::new("archive", &current_path).to(&tar_filename).bytes(size).conflict(conflict));
+            if !flags.keep {
+                report.plan(wipe_action(&current_path, size, flags));
             }
-            current_path = encrypted_path;
+            report.succeeded += 1;
+            return Ok(());
+        }
+
+        create_compress_encrypt_tar(&current_path, &tar_filename, keyring, flags.encrypt_filenames)?;
+        if !flags.keep {
+            secure_delete_directory(&current_path, flags.skip_dod).map_err(|e| {
+                io::Error::new(e.kind(), format!("Failed to securely delete original directory {}: {}", current_path.display(), e))
+            })?;
         }
+        report.succeeded += 1;
+        return Ok(());
+    }
+
+    // The name of the directory's counterpart, if it changes: encrypted, or decrypted back. Archives
+    // get the encrypted name instead, renaming first would encrypt the name twice.
+    let new_name = if !renamable {
+        None
+    } else if flags.encrypt && flags.encrypt_filenames && !renamed_before {
+        let target = encrypted_path(&target_dir.join(file_name(&current_path)?), keyring, true, &format!(".{}", dir_lockit_extension), flags.dry_run)?;
+        Some(target)
+    } else if !flags.encrypt && is_encrypted_directory(&current_path) {
+        // Strip the ".dir.lockit" and decrypt the directory name back to the original
+        let decrypted_dir_name = decrypt_name(&current_path.with_extension(""), keyring, flags.encrypt_filenames)?;
+        Some(target_dir.join(decrypted_dir_name))
     } else {
-        // Decrypt the directory if it ends with the .lockit extension
-        if current_path.extension().and_then(|ext| ext.to_str()) == Some(CUSTOM_EXTENSION)
-            && current_path
-                .file_stem()
-                .and_then(|stem| stem.to_str())
-                .map_or(false, |stem| stem.ends_with(CUSTOM_DIRECTORY_EXTENSION))
-        {
-            // Strip the ".dir.lockit" to get the encrypted directory name
-            let encrypted_dir_name = current_path
-                .file_stem()?
-                .to_str()?
-                .trim_end_matches(&format!(".{}", CUSTOM_DIRECTORY_EXTENSION))
-                .to_string();
-
-            // Decrypt the directory name back to the original
-            let decrypted_dir_name = if encrypt_filenames {
-                decrypt_filename(&encrypted_dir_name, password)?
-            } else {
-                encrypted_dir_name
-            };
+        None
+    };
 
-            let decrypted_path = current_path.with_file_name(decrypted_dir_name);
+    let (output_path, conflict) = match new_name {
+        Some(target) => {
+            let conflict = conflict(&target);
+            let Some(output_path) = resolve_output(&target)? else {
+                report.skip(directory_path, &already_exists(&target));
+                return Ok(());
+            };
+            (output_path, conflict)
+        }
+        None if flags.in_place() => (current_path.clone(), None),
+        None if renamable => (target_dir.join(file_name(&current_path)?), None),
+        None => (target_dir.to_path_buf(), None),
+    };
 
-            if let Err(e) = fs::rename(&current_path, &decrypted_path) {
-                eprintln!("Failed to rename directory {}: {}", current_path.display(), e);
-                return None;
+    // In place, a dry run lists the entries under the name the directory has now
+    let output_path = match (flags.dry_run, flags.in_place()) {
+        (true, true) if output_path != current_path => {
+            report.plan(PlannedAction::new("rename", &current_path).to(&output_path).conflict(conflict));
+            current_path.clone()
+        }
+        (true, false) if !exists(&output_path) || conflict.is_some() => {
+            report.plan(PlannedAction::new("create", &current_path).to(&output_path).conflict(conflict));
+            output_path
+        }
+        (false, true) if output_path != current_path => {
+            remove_existing(&output_path)?;
+            rename(&current_path, &output_path)?;
+            if !flags.encrypt {
+                remove_long_name_sidecar(&current_path.with_extension(""))?;
             }
-            current_path = decrypted_path;
+            current_path = output_path.clone();
+            output_path
+        }
+        // An existing counterpart, such as the source itself with --keep, is written into
+        (false, false) => {
+            fs::create_dir_all(&output_path)
+                .map_err(|e| io::Error::new(e.kind(), format!("Failed to create directory {}: {}", output_path.display(), e)))?;
+            output_path
+        }
+        _ => output_path,
+    };
+
+    let entries = fs::read_dir(&current_path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to read directory {}: {}", current_path.display(), e)))?;
+
+    // Collected up front, since outputs and their temporary files appear while entries are processed
+    let mut entry_paths = Vec::new();
+    for entry in entries {
+        match entry {
+            Ok(entry) => entry_paths.push(entry.path()),
+            Err(e) => report.fail(&current_path, e.into()),
         }
     }
 
-    if dir_mode && encrypt {
-        if let Some(encrypted_tar_data) = create_compress_encrypt_tar(&current_path, password) {
-            let new_filename = get_new_filename(&current_path, password, true, encrypt_filenames)?;
-            let tar_filename = current_path.with_file_name(format!("{}.{}.{}", new_filename, CUSTOM_DIRECTORY_EXTENSION, CUSTOM_EXTENSION));
+    for entry_path in entry_paths {
+        if is_name_file(&entry_path) || is_output_dir(&entry_path, flags) {
+            continue;
+        }
+        if entry_path.is_file() {
+            process_file(&entry_path, &output_path, keyring, flags, report);
+        } else if entry_path.is_dir() {
+            process_directory_into(&entry_path, &output_path, keyring, flags, report);
+        }
+    }
 
-            if fs::write(&tar_filename, &encrypted_tar_data).is_err() {
-                eprintln!("Failed to write encrypted tar file {}", tar_filename.display());
-            } else if secure_delete_directory(&current_path, skip_dod).is_err() {
-                eprintln!("Failed to securely delete original directory {}", current_path.display());
-            }
+    if flags.dry_run {
+        return Ok(());
+    }
+    if !flags.encrypt {
+        remove_unused_name_key(&current_path)?;
+    }
+    // Moved elsewhere, the source is removed once everything in it has been moved
+    if !flags.keep && !flags.in_place() && renamable {
+        let _ = fs::remove_dir(&current_path);
+        if !flags.encrypt && !exists(&current_path) {
+            remove_long_name_sidecar(&current_path.with_extension(""))?;
         }
-    } else {
-        for entry in match fs::read_dir(&current_path) {
-            Ok(entries) => entries,
-            Err(e) => {
-                eprintln!("Failed to read directory {}: {}", current_path.display(), e);
-                return None;
-            }
-        } {
-            let entry_path = match entry {
-                Ok(entry) => entry.path(),
-                Err(e) => {
-                    eprintln!("Failed to read entry in directory {}: {}", current_path.display(), e);
-                    continue;
-                }
-            };
+    }
+    Ok(())
+}
+
+// An output directory inside the tree being processed is not processed itself
+fn is_output_dir(path: &Path, flags: &Flags) -> bool {
+    flags.output_dir.is_some_and(|output_dir| path.is_dir() && fs::canonicalize(path).is_ok_and(|path| path == output_dir))
+}
+
+fn create_compress_encrypt_tar(directory_path: &Path, tar_path: &Path, keyring: &Keyring, encrypt_filenames: bool) -> Result<()> {
+    write_atomically(
+        tar_path,
+        |output| {
+            let metadata = Metadata::read_from(directory_path)?;
+            write_encrypted(output, keyring, FLAG_ARCHIVE | name_flags(encrypt_filenames), &metadata, |writer| {
+                let mut tar_builder = tar::Builder::new(writer);
+                tar_builder.append_dir_all(".", directory_path)?;
+                tar_builder.finish()
+            })?;
+            Ok(())
+        },
+        |temp_path, ()| verify_encrypted(temp_path, keyring, None),
+    )
+}
+
+fn decrypt_and_extract_dir_lockit(file_path: &Path, target_dir: &Path, keyring: &Keyring, flags: &Flags) -> Result<Outcome> {
+    let (metadata, reader) = open_decrypted(file_path, keyring)?;
+    let target = target_dir.join(original_name(&file_path.with_extension(""), metadata.as_ref(), keyring, flags)?);
+    let conflict = conflict(&target);
+    let Some(extraction_path) = resolve_output(&target)? else {
+        return Ok(Outcome::Skipped(already_exists(&target)));
+    };
+    if flags.dry_run {
+        return plan_file("extract", file_path, &extraction_path, conflict, flags);
+    }
+
+    // Extracted next to the final directory first, so a failed or interrupted extraction never
+    // leaves a partial tree under the final name
+    let temp_path = temp_sibling(&extraction_path);
+    let result = fs::create_dir(&temp_path).map_err(LockitError::from).and_then(|()| {
+        Archive::new(reader).unpack(&temp_path)?;
+        sync_tree(&temp_path)?;
+        if let Some(metadata) = &metadata {
+            metadata.apply_to_directory(&temp_path)?;
+        }
+        remove_existing(&extraction_path)?;
+        fs::rename(&temp_path, &extraction_path)?;
+        sync_parent(&extraction_path)
+    });
+    if let Err(e) = result {
+        let _ = fs::remove_dir_all(&temp_path);
+        return Err(e);
+    }
+    if !flags.keep {
+        delete_original(file_path, flags.skip_dod)?;
+        remove_long_name_sidecar(&file_path.with_extension(""))?;
+    }
 
-            if entry_path.is_file() {
-                process_file_with_flags(&entry_path, password, encrypt, encrypt_filenames, skip_dod);
-            } else if entry_path.is_dir() {
-                process_directory_with_flags(&entry_path, password, encrypt, encrypt_filenames, dir_mode, skip_dod)?;
+    Ok(Outcome::Done)
+}
+
+fn conflict_policy() -> ConflictPolicy {
+    ConflictPolicy::from_name(&config::get().on_conflict.value).unwrap_or(ConflictPolicy::Fail)
+}
+
+// Applies the conflict policy to an output path. Returns the path to write to, or `None` when the
+// input is to be skipped.
+fn resolve_output(path: &Path) -> Result<Option<PathBuf>> {
+    resolve_output_with(path, conflict_policy())
+}
+
+fn resolve_output_with(path: &Path, policy: ConflictPolicy) -> Result<Option<PathBuf>> {
+    if !exists(path) {
+        return Ok(Some(path.to_path_buf()));
+    }
+
+    match policy {
+        ConflictPolicy::Fail => Err(LockitError::Io(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{}, use --on-conflict to skip, rename or overwrite", already_exists(path)),
+        ))),
+        ConflictPolicy::Skip => Ok(None),
+        ConflictPolicy::Overwrite => Ok(Some(path.to_path_buf())),
+        ConflictPolicy::Rename => {
+            let mut number = 1;
+            loop {
+                let candidate = numbered_path(path, number)?;
+                if !exists(&candidate) {
+                    return Ok(Some(candidate));
+                }
+                number += 1;
             }
         }
     }
-    Some(())
 }
 
-fn create_compress_encrypt_tar(directory_path: &Path, password: &str) -> Option<Vec<u8>> {
-    let mut tar_data = Vec::new();
-    {
-        let mut tar_builder = tar::Builder::new(&mut tar_data);
-        if tar_builder.append_dir_all(".", directory_path).is_err() || tar_builder.finish().is_err() {
-            eprintln!("Failed to create or finalize tar archive");
-            return None;
+// Numbered before the first extension, so `a.txt.lockit` becomes `a (1).txt.lockit` and still
+// decrypts to a `.txt` file. Leading dots of hidden files are not extensions.
+fn numbered_path(path: &Path, number: u32) -> Result<PathBuf> {
+    let mut stem = Path::new(file_name(path)?);
+    let mut extensions = Vec::new();
+    while let (Some(shorter), Some(extension)) = (stem.file_stem(), stem.extension()) {
+        extensions.push(extension);
+        stem = Path::new(shorter);
+    }
+
+    let mut name = stem.as_os_str().to_os_string();
+    name.push(format!(" ({})", number));
+    for extension in extensions.iter().rev() {
+        name.push(".");
+        name.push(extension);
+    }
+    Ok(path.with_file_name(name))
+}
+
+// The policy a dry run lists for a target that already exists
+fn conflict(path: &Path) -> Option<String> {
+    exists(path).then(|| config::get().on_conflict.value.clone())
+}
+
+// Writing the output of a file, then wiping the file unless it is kept
+fn plan_file(action: &'static str, file_path: &Path, output_path: &Path, conflict: Option<String>, flags: &Flags) -> Result<Outcome> {
+    let size = fs::metadata(file_path)?.len();
+    let mut actions = vec![PlannedAction::new(action, file_path).to(output_path).bytes(size).conflict(conflict)];
+    if !flags.keep {
+        actions.push(wipe_action(file_path, size, flags));
+    }
+    Ok(Outcome::Planned(actions))
+}
+
+fn wipe_action(path: &Path, size: u64, flags: &Flags) -> PlannedAction {
+    PlannedAction::new(if flags.skip_dod { "delete" } else { "wipe" }, path).bytes(size)
+}
+
+// Total size of the files in a tree, as wiped by `secure_delete_directory`
+pub fn tree_size(path: &Path) -> io::Result<u64> {
+    tree_stats(path).map(|(_, size)| size)
+}
+
+// Number of files and their total size below a path
+pub fn tree_stats(path: &Path) -> io::Result<(u64, u64)> {
+    if !path.is_dir() {
+        return Ok((1, fs::metadata(path)?.len()));
+    }
+    let (mut files, mut size) = (0, 0);
+    for entry in fs::read_dir(path)? {
+        let (entry_files, entry_size) = tree_stats(&entry?.path())?;
+        files += entry_files;
+        size += entry_size;
+    }
+    Ok((files, size))
+}
+
+fn already_exists(path: &Path) -> String {
+    format!("{} already exists", path.display())
+}
+
+// Also true for dangling symlinks, which would otherwise be followed when writing
+fn exists(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+// Only reached with --on-conflict=overwrite, where an existing directory is replaced as a whole
+fn remove_existing(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(_) => Ok(()),
+    }
+}
+
+// Writes a new file through a temporary sibling that is synced, checked by `verify` and only then
+// renamed over `path`, so a crash or a full disk never leaves a truncated file under the final name.
+// `verify` receives the temporary path and whatever `write` returned. On Unix the temporary file
+// starts out readable only by its owner, until `write` restores the original permissions.
+fn write_atomically<T>(
+    path: &Path,
+    write: impl FnOnce(&mut File) -> Result<T>,
+    verify: impl FnOnce(&Path, T) -> Result<()>,
+) -> Result<()> {
+    let temp_path = temp_sibling(path);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let result = options
+        .open(&temp_path)
+        .map_err(LockitError::from)
+        .and_then(|mut file| {
+            let written = write(&mut file)?;
+            file.sync_all()?;
+            drop(file);
+            verify(&temp_path, written)?;
+            fs::rename(&temp_path, path)?;
+            sync_parent(path)
+        });
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+// Short random names keep temporary files within name length limits next to long encrypted names
+fn temp_sibling(path: &Path) -> PathBuf {
+    path.with_file_name(format!(".lockit-{}.tmp", hex::encode(rand::random::<[u8; 8]>())))
+}
+
+// Makes a rename in the parent directory durable. Directories cannot be synced on Windows.
+fn sync_parent(path: &Path) -> Result<()> {
+    Ok(sync_path(parent_dir(path))?)
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."))
+}
+
+fn sync_path(path: &Path) -> io::Result<()> {
+    if cfg!(unix) {
+        File::open(path)?.sync_all()?;
+    }
+    Ok(())
+}
+
+fn sync_tree(directory_path: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(directory_path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            sync_tree(&entry.path())?;
+        } else if file_type.is_file() {
+            sync_path(&entry.path())?;
         }
     }
+    sync_path(directory_path)
+}
 
-    match super::compression::compress_data(&tar_data)
-        .and_then(|data| super::crypto::encrypt_data(&data, password)) {
-            Ok(encrypted_data) => Some(encrypted_data),
-            Err(_) => {
-                eprintln!("Failed to compress or encrypt tar archive");
-                None
-            }
+// Reads a freshly written ciphertext back in full, which authenticates every chunk, and checks that
+// it decompresses to the expected length
+fn verify_encrypted(path: &Path, keyring: &Keyring, expected_len: Option<u64>) -> Result<()> {
+    match decrypt_to_sink(path, keyring) {
+        Ok(len) if expected_len.is_none_or(|expected| expected == len) => Ok(()),
+        Ok(len) => Err(LockitError::Io(io::Error::other(format!(
+            "Verification failed: wrote {} bytes, but {} bytes decrypt back",
+            expected_len.unwrap_or_default(),
+            len
+        )))),
+        Err(e) => Err(LockitError::Io(io::Error::other(format!("Verification failed, the written file does not decrypt: {}", e)))),
     }
 }
 
-fn decrypt_and_extract_dir_lockit(file_path: &Path, password: &str, encrypt_filenames: bool, skip_dod: bool) -> Option<PathBuf> {
-    if let Some(decrypted_data) = decompress_and_decrypt_tar(file_path, password) {
-        let encrypted_dir_name = file_path.with_extension("").file_stem()?.to_string_lossy().to_string();
-        let decrypted_dir_name = if encrypt_filenames {
-            decrypt_filename(&encrypted_dir_name, password)?
-        } else {
-            encrypted_dir_name
+// Encrypts a file, or a directory as an archive, to `output` and leaves it in place. The original
+// name goes into the metadata block only, as there is no name on disk to encrypt.
+pub fn encrypt_to_writer(path: &Path, keyring: &Keyring, dir_mode: bool, output: impl Write) -> Result<()> {
+    let metadata = Metadata::read_from(path)?;
+    let mut output = if path.is_dir() {
+        if !dir_mode {
+            return Err(LockitError::Usage(format!("{} is a directory, add --zipdir to write it as an archive", path.display())));
+        }
+        write_encrypted(output, keyring, FLAG_ARCHIVE, &metadata, |writer| {
+            let mut tar_builder = tar::Builder::new(writer);
+            tar_builder.append_dir_all(".", path)?;
+            tar_builder.finish()
+        })?
+    } else {
+        let mut input = File::open(path)?;
+        write_encrypted(output, keyring, 0, &metadata, |writer| io::copy(&mut input, writer).map(|_| ()))?
+    };
+    Ok(output.flush()?)
+}
+
+// Decrypts a file or archive, or standard input for `-`, to `output` and leaves it in place.
+// Archives come out as tar streams. Streams written by `--pipe` are accepted as well.
+pub fn decrypt_to_writer(path: &Path, keyring: &Keyring, output: impl Write) -> Result<()> {
+    let mut input: Box<dyn Read> = match is_stdio(path) {
+        true => Box::new(io::stdin().lock()),
+        false => Box::new(BufReader::new(File::open(path)?)),
+    };
+
+    // The header tells a pipe stream apart; it is read again by the decryptor
+    let (consumed, header) = read_header(&mut input)?;
+    let input = io::Cursor::new(consumed).chain(input);
+    let mut output = BufWriter::new(output);
+    if header.is_some_and(|header| header.extension(EXT_RECORDS).is_some()) {
+        pipe::decrypt_pipe(input, &mut output, keyring)?;
+    } else {
+        let (_, mut reader) = decrypt_input(input, keyring)?;
+        io::copy(&mut reader, &mut output)?;
+    }
+    Ok(output.flush()?)
+}
+
+// Returns the length of the decrypted contents, without the metadata block
+fn decrypt_to_sink(path: &Path, keyring: &Keyring) -> Result<u64> {
+    let (_, mut reader) = open_decrypted(path, keyring)?;
+    Ok(io::copy(&mut reader, &mut io::sink())?)
+}
+
+// Compresses and encrypts the metadata block followed by whatever `write_plain` produces straight
+// into `output`, chunk by chunk
+fn write_encrypted<W: Write>(
+    output: W,
+    keyring: &Keyring,
+    flags: u8,
+    metadata: &Metadata,
+    write_plain: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+) -> Result<W> {
+    let extensions = vec![(header::EXT_METADATA, Vec::new())];
+    let encryptor = StreamEncryptor::new(BufWriter::new(output), keyring, Compression::Zstd, flags, extensions)?;
+    let mut encoder = super::compression::compress_writer(encryptor)?;
+    encoder.write_all(&metadata.encode())?;
+    write_plain(&mut encoder)?;
+    let writer = encoder.finish()?.finish()?;
+    Ok(writer.into_inner().map_err(|e| e.into_error())?)
+}
+
+// Returns the metadata block, which files written before it existed lack, and a reader for the contents
+fn open_decrypted(file_path: &Path, keyring: &Keyring) -> Result<(Option<Metadata>, Box<dyn Read>)> {
+    decrypt_input(BufReader::new(File::open(file_path)?), keyring)
+}
+
+fn decrypt_input(input: impl Read + 'static, keyring: &Keyring) -> Result<(Option<Metadata>, Box<dyn Read>)> {
+    let (header, reader) = super::crypto::decrypt_reader(input, keyring)?;
+    let mut reader = super::compression::decompress_reader(header.compression, reader)?;
+    let metadata = match header.extension(header::EXT_METADATA) {
+        Some(_) => Some(Metadata::read(&mut reader)?),
+        None => None,
+    };
+    Ok((metadata, reader))
+}
+
+// Names written before per-directory name keys embed a whole header and are hex-encoded
+fn decrypt_filename(hex_encoded: &str, keyring: &Keyring) -> Result<String> {
+    let encrypted_data = hex::decode(hex_encoded)
+        .map_err(|_| LockitError::Corrupt(format!("Encrypted name {} is not valid hex", hex_encoded)))?;
+    let (_, decrypted_data) = super::crypto::decrypt_data(&encrypted_data, keyring)?;
+    String::from_utf8(decrypted_data).map_err(|_| LockitError::Corrupt(format!("Decrypted name {} is not valid UTF-8", hex_encoded)))
+}
+
+// Those with a lockit header are recognized whatever the command line says; headerless ones
+// from before the header existed rely on the flag
+fn is_legacy_encrypted_name(name: &str, encrypt_filenames: bool) -> bool {
+    hex::decode(name).is_ok_and(|data| encrypt_filenames || header::has_magic(&data))
+}
+
+fn name_flags(encrypt_filenames: bool) -> u8 {
+    if encrypt_filenames { FLAG_ENCRYPTED_NAME } else { 0 }
+}
+
+// The encrypted counterpart of `path`: its name, encrypted when `encrypt_filenames` is set,
+// followed by `suffix`. Encrypted names that would not fit in a path component are replaced by
+// a hash, and the encrypted name goes into a sidecar next to the entry. A dry run writes neither
+// the sidecar nor a new name key; names under a key that does not exist yet cannot be known.
+fn encrypted_path(path: &Path, keyring: &Keyring, encrypt_filenames: bool, suffix: &str, dry_run: bool) -> Result<PathBuf> {
+    let name = file_name(path)?;
+    if !encrypt_filenames {
+        let mut name = name.to_os_string();
+        name.push(suffix);
+        return Ok(path.with_file_name(name));
+    }
+
+    let name = names::name_to_bytes(name).ok_or_else(|| {
+        LockitError::Unsupported(format!("File name of {} is not valid Unicode and cannot be encrypted", path.display()))
+    })?;
+    let name_key = match dry_run {
+        true => load_name_keys(&name_key_paths(parent_dir(path)), keyring)?.into_iter().next(),
+        false => Some(name_key_for_writing(parent_dir(path), keyring)?),
+    };
+    let Some(name_key) = name_key else {
+        return Ok(path.with_file_name(format!("{}{}", NEW_ENCRYPTED_NAME, suffix)));
+    };
+
+    let encrypted = name_key.encrypt_name(name)?;
+    if encrypted.len() + suffix.len() <= names::MAX_NAME_LEN {
+        return Ok(path.with_file_name(format!("{}{}", encrypted, suffix)));
+    }
+
+    let long_name = names::long_name(&encrypted);
+    let sidecar = path.with_file_name(format!("{}.{}", long_name, LONG_NAME_EXTENSION));
+    if !dry_run {
+        write_atomically(&sidecar, |file| Ok(file.write_all(encrypted.as_bytes())?), |_, ()| Ok(()))?;
+    }
+    Ok(path.with_file_name(format!("{}{}", long_name, suffix)))
+}
+
+// The original name of an entry whose name, without its suffix, is the file stem of `path`.
+// Names in the clear are returned unchanged.
+fn decrypt_name(path: &Path, keyring: &Keyring, encrypt_filenames: bool) -> Result<OsString> {
+    let stem = path.file_stem().ok_or_else(|| missing_name(path))?;
+    // Encrypted names are ASCII, so anything else is a name in the clear
+    let Some(stem) = stem.to_str() else {
+        return Ok(stem.to_os_string());
+    };
+    let legacy = is_legacy_encrypted_name(stem, encrypt_filenames);
+
+    let directory = parent_dir(path);
+    let key_paths = name_key_paths(directory);
+    // The name key stays in the directory the name was encrypted in, so an entry moved out of it
+    // cannot get its name back. Decrypting it under the encrypted name would hide that.
+    if names::looks_encrypted(stem) && key_paths.is_empty() && !legacy {
+        return Err(LockitError::WrongKey(format!(
+            "Name is encrypted, but {} has no {} to decrypt it; move the entry back to the directory it was encrypted in",
+            directory.display(),
+            names::NAME_KEY_FILE
+        )));
+    }
+    if names::looks_encrypted(stem) && !key_paths.is_empty() {
+        let encrypted = match long_name_sidecar(path) {
+            Some(sidecar) => fs::read_to_string(&sidecar)
+                .map_err(|e| io::Error::new(e.kind(), format!("Cannot read long name {}: {}", sidecar.display(), e)))?,
+            None => stem.to_string(),
         };
-        let extraction_path = Path::new(&decrypted_dir_name);
 
-        if extract_tar_archive(&decrypted_data, extraction_path).is_err() {
-            eprintln!("Failed to extract tar archive");
-            return None;
-        } else if secure_delete(file_path, skip_dod).is_err() {
-            eprintln!("Failed to securely delete encrypted tar file {}", file_path.display());
+        let name_keys = load_name_keys(&key_paths, keyring)?;
+        if let Some(name) = name_keys.iter().find_map(|name_key| name_key.decrypt_name(encrypted.trim()).ok()) {
+            return names::name_from_bytes(name)
+                .ok_or_else(|| LockitError::Unsupported(format!("Decrypted name of {} is not valid UTF-8", stem)));
+        }
+        if !legacy {
+            return Err(match name_keys.is_empty() {
+                true => LockitError::WrongKey(format!("Names in {} were encrypted with another key", directory.display())),
+                false => LockitError::Corrupt(format!("Encrypted name {} is damaged or was not encrypted with this key", stem)),
+            });
         }
+    }
 
-        return Some(extraction_path.to_path_buf());
+    if legacy { decrypt_filename(stem, keyring).map(OsString::from) } else { Ok(OsString::from(stem)) }
+}
+
+// The name recorded in the metadata wins; files without one fall back to the name on disk
+fn original_name(file_path: &Path, metadata: Option<&Metadata>, keyring: &Keyring, flags: &Flags) -> Result<OsString> {
+    if let Some(name) = metadata.map(Metadata::file_name).transpose()?.flatten() {
+        return Ok(name.as_os_str().to_os_string());
     }
+    decrypt_name(file_path, keyring, flags.encrypt_filenames)
+}
+
+// A directory has one name key for every key that wrote encrypted names into it, as encrypting to
+// recipients cannot open a name key written in an earlier run: `.lockit-names`, `.lockit-names.1`,
+// `.lockit-names.2` and so on
+fn name_key_path(directory: &Path, number: u32) -> PathBuf {
+    match number {
+        0 => directory.join(NAME_KEY_FILE),
+        n => directory.join(format!("{}.{}", NAME_KEY_FILE, n)),
+    }
+}
 
-    None
+fn name_key_paths(directory: &Path) -> Vec<PathBuf> {
+    (0..).map(|number| name_key_path(directory, number)).take_while(|path| exists(path)).collect()
 }
 
-fn decompress_and_decrypt_tar(tar_file_path: &Path, password: &str) -> Option<Vec<u8>> {
-    let encrypted_data = match fs::read(tar_file_path) {
-        Ok(data) => data,
-        Err(e) => {
-            eprintln!("Error reading encrypted tar file {}: {}", tar_file_path.display(), e);
-            return None;
+// The name keys this keyring can open; those written under other keys are passed over
+fn load_name_keys(key_paths: &[PathBuf], keyring: &Keyring) -> Result<Vec<NameKey>> {
+    let mut name_keys = Vec::new();
+    for key_path in key_paths {
+        match NameKey::open(&fs::read(key_path)?, keyring) {
+            Ok(name_key) => name_keys.push(name_key),
+            Err(LockitError::WrongKey(_)) => {}
+            Err(e) => return Err(e),
         }
-    };
+    }
+    Ok(name_keys)
+}
+
+// Created with the first encrypted name a key writes into a directory
+fn name_key_for_writing(directory: &Path, keyring: &Keyring) -> Result<NameKey> {
+    let key_paths = name_key_paths(directory);
+    if let Some(name_key) = load_name_keys(&key_paths, keyring)?.into_iter().next() {
+        return Ok(name_key);
+    }
 
-    let decrypted_data = match super::crypto::decrypt_data(&encrypted_data, password) {
-        Ok(data) => data,
-        Err(e) => {
-            eprintln!("Error decrypting tar file {}: {}", tar_file_path.display(), e);
-            return None;
+    let name_key = NameKey::generate();
+    let sealed = name_key.seal(keyring)?;
+    let key_path = name_key_path(directory, key_paths.len() as u32);
+    write_atomically(&key_path, |file| Ok(file.write_all(&sealed)?), |_, ()| Ok(()))?;
+    Ok(name_key)
+}
+
+// Once nothing in a directory has an encrypted name any more, its name key is removed as well
+pub fn remove_unused_name_key(directory: &Path) -> Result<()> {
+    let key_paths = name_key_paths(directory);
+    if key_paths.is_empty() {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(directory)? {
+        if has_encrypted_name(&entry?.path()) {
+            return Ok(());
         }
+    }
+    // Removed from the last, so an interruption never leaves a gap in the numbering
+    for key_path in key_paths.iter().rev() {
+        fs::remove_file(key_path)?;
+    }
+    Ok(sync_path(directory)?)
+}
+
+// Encrypted entries and the sidecars of their long names
+fn has_encrypted_name(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
     };
+    let Some(stem) = name.strip_suffix(&format!(".{}", extension())) else {
+        return name.starts_with(names::LONG_NAME_PREFIX);
+    };
+    let stem = stem.strip_suffix(&format!(".{}", directory_extension())).unwrap_or(stem);
+    names::looks_encrypted(stem)
+}
 
-    let decompressed_data = match super::compression::decompress_data(&decrypted_data) {
-        Ok(data) => data,
-        Err(e) => {
-            eprintln!("Error decompressing tar file {}: {}", tar_file_path.display(), e);
-            return None;
-        }
+// Name keys and long name sidecars are handled along with the entries they belong to
+fn is_name_file(path: &Path) -> bool {
+    let is_name_key = |name: &str| {
+        name.strip_prefix(NAME_KEY_FILE)
+            .is_some_and(|rest| rest.is_empty() || rest.strip_prefix('.').is_some_and(|n| n.parse::<u32>().is_ok()))
     };
+    path.file_name().and_then(|name| name.to_str()).is_some_and(is_name_key)
+        || (path.extension().is_some_and(|ext| ext == LONG_NAME_EXTENSION)
+            && path.file_stem().and_then(|stem| stem.to_str()).is_some_and(|stem| stem.starts_with(names::LONG_NAME_PREFIX)))
+}
+
+// `path` is the entry without its suffix, as for `decrypt_name`
+fn long_name_sidecar(path: &Path) -> Option<PathBuf> {
+    let stem = path.file_stem()?.to_str()?;
+    stem.starts_with(names::LONG_NAME_PREFIX)
+        .then(|| path.with_file_name(format!("{}.{}", stem, LONG_NAME_EXTENSION)))
+}
+
+// The sidecar of a long name is only needed until its entry is decrypted
+fn remove_long_name_sidecar(path: &Path) -> Result<()> {
+    match long_name_sidecar(path).map(fs::remove_file) {
+        Some(Err(e)) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// `-` on the command line stands for standard input, or standard output where data is written
+pub fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+fn file_name(path: &Path) -> Result<&OsStr> {
+    path.file_name().ok_or_else(|| missing_name(path))
+}
 
-    Some(decompressed_data)
+fn missing_name(path: &Path) -> LockitError {
+    LockitError::Unsupported(format!("File name of {} is missing", path.display()))
 }
 
-fn extract_tar_archive(tar_data: &[u8], output_dir: &Path) -> io::Result<()> {
-    let cursor = Cursor::new(tar_data);
-    let mut archive = Archive::new(cursor);
-    archive.unpack(output_dir)
+// Compares the raw bytes, so names that are not valid UTF-8 are matched too
+fn has_suffix(path: &Path, suffix: &str) -> bool {
+    path.file_name().is_some_and(|name| name.as_encoded_bytes().ends_with(suffix.as_bytes()))
 }
 
-fn encrypt_filename(filename: &str, password: &str) -> Option<String> {
-    super::crypto::encrypt_data(filename.as_bytes(), password)
-        .ok()
-        .map(hex::encode)
+fn rename(from: &Path, to: &Path) -> Result<()> {
+    fs::rename(from, to).map_err(|e| io::Error::new(e.kind(), format!("Failed to rename {}: {}", from.display(), e)))?;
+    sync_parent(to)
 }
 
-fn decrypt_filename(hex_encoded: &str, password: &str) -> Option<String> {
-    hex::decode(hex_encoded).ok()
-        .and_then(|encrypted_data| super::crypto::decrypt_data(&encrypted_data, password).ok())
-        .and_then(|decrypted_data| String::from_utf8(decrypted_data).ok())
+// Only called once the output is complete and durable under its final name
+fn delete_original(file_path: &Path, skip_dod: bool) -> Result<()> {
+    secure_delete(file_path, skip_dod)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to securely delete {}: {}", file_path.display(), e)).into())
 }
 
-fn get_new_filename(file_path: &Path, password: &str, encrypt: bool, encrypt_filenames: bool) -> Option<String> {
-    if encrypt_filenames {
-        match encrypt {
-            true => file_path.file_name()?.to_str().and_then(|name| encrypt_filename(name, password)),
-            false => file_path.file_stem()?.to_str().and_then(|name| decrypt_filename(name, password)),
+fn compress_and_encrypt_file(file_path: &Path, target_dir: &Path, keyring: &Keyring, flags: &Flags) -> Result<Outcome> {
+    let mut input = File::open(file_path)?;
+    let metadata = Metadata::read_from(file_path)?;
+
+    let target = encrypted_path(&target_dir.join(file_name(file_path)?), keyring, flags.encrypt_filenames, &format!(".{}", extension()), flags.dry_run)?;
+    let conflict = conflict(&target);
+    let Some(new_file_path) = resolve_output(&target)? else {
+        return Ok(Outcome::Skipped(already_exists(&target)));
+    };
+    if flags.dry_run {
+        return plan_file("encrypt", file_path, &new_file_path, conflict, flags);
+    }
+
+    write_atomically(
+        &new_file_path,
+        |output| {
+            let mut plain_len = 0;
+            write_encrypted(output, keyring, name_flags(flags.encrypt_filenames), &metadata, |writer| {
+                plain_len = io::copy(&mut input, writer)?;
+                Ok(())
+            })?;
+            Ok(plain_len)
+        },
+        |temp_path, plain_len| verify_encrypted(temp_path, keyring, Some(plain_len)),
+    )?;
+    if !flags.keep {
+        delete_original(file_path, flags.skip_dod)?;
+    }
+    Ok(Outcome::Done)
+}
+
+fn decompress_and_decrypt_file(file_path: &Path, target_dir: &Path, keyring: &Keyring, flags: &Flags) -> Result<Outcome> {
+    let (metadata, mut reader) = open_decrypted(file_path, keyring)?;
+    let target = target_dir.join(original_name(file_path, metadata.as_ref(), keyring, flags)?);
+    let conflict = conflict(&target);
+    let Some(output_path) = resolve_output(&target)? else {
+        return Ok(Outcome::Skipped(already_exists(&target)));
+    };
+    if flags.dry_run {
+        return plan_file("decrypt", file_path, &output_path, conflict, flags);
+    }
+
+    // Every chunk is authenticated while it is read, so reaching the end is the verification
+    write_atomically(
+        &output_path,
+        |output| {
+            let mut writer = BufWriter::new(output);
+            io::copy(&mut reader, &mut writer)?;
+            let output = writer.into_inner().map_err(|e| e.into_error())?;
+            // Last, so the restored times are not changed by the writes
+            match &metadata {
+                Some(metadata) => metadata.apply_to_file(output),
+                None => Ok(()),
+            }
+        },
+        |_, ()| Ok(()),
+    )?;
+    if !flags.keep {
+        delete_original(file_path, flags.skip_dod)?;
+        remove_long_name_sidecar(file_path)?;
+    }
+    Ok(Outcome::Done)
+}
+
+// Decrypts every file and archive under `path` into nothing, leaving the tree untouched. Each one
+// is listed on stdout with its outcome; the details of failures go to the report.
+pub fn verify_path(path: &Path, keyring: &Keyring, report: &mut Report) {
+    if path.is_dir() {
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                let e = io::Error::new(e.kind(), format!("Failed to read directory {}: {}", path.display(), e));
+                return report.fail(path, e.into());
+            }
+        };
+
+        let mut entry_paths: Vec<PathBuf> = Vec::new();
+        for entry in entries {
+            match entry {
+                Ok(entry) => entry_paths.push(entry.path()),
+                Err(e) => report.fail(path, e.into()),
+            }
+        }
+        entry_paths.sort();
+
+        // Only files found by the walk are filtered by extension; named files are always tried
+        for entry_path in entry_paths.iter().filter(|entry_path| !is_name_file(entry_path)) {
+            if !entry_path.is_dir() && !has_suffix(entry_path, &format!(".{}", extension())) {
+                report.skip(entry_path, "unsupported extension");
+                continue;
+            }
+            verify_path(entry_path, keyring, report);
         }
-    } else {
-        file_path.file_name()?.to_str().map(String::from)
+        return;
     }
+
+    let result = decrypt_to_writer(path, keyring, io::sink());
+    let status = match &result {
+        Ok(()) => "OK",
+        Err(LockitError::WrongKey(_)) => "WRONG KEY",
+        Err(LockitError::Corrupt(_)) => "CORRUPT",
+        Err(LockitError::Unsupported(_)) => "UNSUPPORTED",
+        Err(_) => "FAILED",
+    };
+    println!("{}: {}", path.display(), status);
+    report.record(path, result);
 }
 
-fn compress_and_encrypt_file(file_path: &Path, password: &str, encrypt_filenames: bool, skip_dod: bool) -> Option<PathBuf> {
-    let file_data = fs::read(file_path).ok()?;
-    let compressed_data = super::compression::compress_data(&file_data).ok()?;
-    let encrypted_data = super::crypto::encrypt_data(&compressed_data, password).ok()?;
+pub fn rekey_path(path: &Path, old: &Keyring, new: &Keyring, encrypt_filenames: bool, report: &mut Report) {
+    if is_stdio(path) {
+        return report.record(path, rekey_stdio(old, new));
+    }
+    if let Err(e) = rekey_entry(path, old, new, encrypt_filenames, false, report) {
+        report.fail(path, e);
+    }
+}
 
-    let new_filename = get_new_filename(file_path, password, true, encrypt_filenames)?;
-    let new_file_path = file_path.with_file_name(format!("{}.{}", new_filename, CUSTOM_EXTENSION));
+// `whole_directory` is set for the entries of a directory being rekeyed, where every sibling
+// sharing the name keys is rekeyed as well
+fn rekey_entry(path: &Path, old: &Keyring, new: &Keyring, encrypt_filenames: bool, whole_directory: bool, report: &mut Report) -> Result<()> {
+    let lockit_extension = format!(".{}", extension());
+    let is_lockit = has_suffix(path, &lockit_extension);
 
-    if fs::write(&new_file_path, &encrypted_data).is_ok() {
-        if let Err(e) = secure_delete(file_path, skip_dod) {
-            eprintln!("Error securely deleting file {}: {}", file_path.display(), e);
+    // Files record whether their name is encrypted; directories follow the command line
+    let mut name_encrypted = encrypt_filenames && is_lockit;
+    if path.is_file() {
+        if !is_lockit {
+            report.skip(path, "unsupported extension");
+            return Ok(());
+        }
+        let flags = rekey_file(path, old, new)?;
+        name_encrypted = flags & FLAG_ENCRYPTED_NAME != 0;
+    }
+
+    // Names under a name key keep their spelling, only the key is re-wrapped. Older names that
+    // embed their own header are encrypted again under the name key.
+    let mut current_path = path.to_path_buf();
+    if name_encrypted {
+        rekey_name_key(parent_dir(path), old, new, whole_directory)?;
+        current_path = rekey_filename(path, old, new)?;
+    }
+
+    if current_path.is_dir() {
+        let entries = fs::read_dir(&current_path)
+            .map_err(|e| io::Error::new(e.kind(), format!("Failed to read directory {}: {}", current_path.display(), e)))?;
+
+        // Collected up front, since renaming entries while iterating could list them again
+        let entries: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).collect();
+        for entry_path in entries.into_iter().filter(|entry_path| !is_name_file(entry_path)) {
+            if let Err(e) = rekey_entry(&entry_path, old, new, encrypt_filenames, true, report) {
+                report.fail(&entry_path, e);
+            }
         }
-        Some(new_file_path)
     } else {
-        eprintln!("Error writing encrypted file {}", new_file_path.display());
-        None
+        report.succeeded += 1;
     }
+    Ok(())
 }
 
-fn decompress_and_decrypt_file(file_path: &Path, password: &str, encrypt_filenames: bool, skip_dod: bool) -> Option<PathBuf> {
-    if file_path.extension()?.to_str()? != CUSTOM_EXTENSION {
-        eprintln!("Skipping file with unsupported extension: {}", file_path.display());
-        return None;
+// Replaces the header of an enveloped file and returns its flags. The payload is copied, but never
+// decrypted.
+fn rekey_file(file_path: &Path, old: &Keyring, new: &Keyring) -> Result<u8> {
+    let mut file = File::open(file_path)?;
+    let (_, header) = read_header(&mut file)?;
+    let header = header.ok_or_else(predates_header)?;
+    let rekeyed = super::crypto::rekey_header(&header, old, new)?.encode();
+
+    // The header holds the only wrapped copy of the data key, so it is never overwritten in place:
+    // a torn write would leave the file undecryptable. The ciphertext is copied as is.
+    write_atomically(
+        file_path,
+        |output| {
+            output.set_permissions(file.metadata()?.permissions())?;
+            output.write_all(&rekeyed)?;
+            io::copy(&mut file, output)?;
+            Ok(())
+        },
+        |_, ()| Ok(()),
+    )?;
+    Ok(header.flags)
+}
+
+// Copies standard input to standard output with the header replaced
+fn rekey_stdio(old: &Keyring, new: &Keyring) -> Result<()> {
+    let mut input = io::stdin().lock();
+    let (_, header) = read_header(&mut input)?;
+    let header = header.ok_or_else(predates_header)?;
+
+    let mut output = io::stdout().lock();
+    output.write_all(&super::crypto::rekey_header(&header, old, new)?.encode())?;
+    io::copy(&mut input, &mut output)?;
+    Ok(output.flush()?)
+}
+
+fn predates_header() -> LockitError {
+    LockitError::Unsupported("File predates the lockit header, decrypt and encrypt it again instead".into())
+}
+
+// Converts a name that embeds its own header to one under the name key of its directory, keeping
+// its `.lockit` / `.dir.lockit` suffix. Other names are left as they are.
+fn rekey_filename(path: &Path, old: &Keyring, new: &Keyring) -> Result<PathBuf> {
+    let Some(file_name) = file_name(path)?.to_str() else {
+        return Ok(path.to_path_buf());
+    };
+    let dir_suffix = format!(".{}.{}", directory_extension(), extension());
+    let suffix = if file_name.ends_with(&dir_suffix) { dir_suffix } else { format!(".{}", extension()) };
+    let encrypted_name = file_name.strip_suffix(&suffix).unwrap_or(file_name);
+    if hex::decode(encrypted_name).is_err() {
+        return Ok(path.to_path_buf());
     }
 
-    let encrypted_data = fs::read(file_path).ok()?;
-    let decrypted_data = super::crypto::decrypt_data(&encrypted_data, password).ok()?;
-    let decompressed_data = super::compression::decompress_data(&decrypted_data).ok()?;
+    let name = decrypt_filename(encrypted_name, old)?;
+    let new_path = encrypted_path(&path.with_file_name(name), new, true, &suffix, false)?;
+    rename(path, &new_path)?;
+    Ok(new_path)
+}
 
-    let new_filename = get_new_filename(file_path, password, false, encrypt_filenames)?;
-    let output_path = file_path.with_file_name(new_filename);
+// Re-wraps the name keys of a directory under `new`. Every entry with an encrypted name asks for
+// this, so keys already written under `new`, and keys `old` cannot open, are left alone. The name
+// keys are shared by the whole directory: unless all of it is rekeyed, the siblings still need the
+// key under `old`, so the re-wrapped key is added as another numbered key file instead.
+fn rekey_name_key(directory: &Path, old: &Keyring, new: &Keyring, whole_directory: bool) -> Result<()> {
+    let key_paths = name_key_paths(directory);
+    let mut rekeyed = load_name_keys(&key_paths, new)?;
+    for key_path in &key_paths {
+        let data = fs::read(key_path)?;
+        if header::parse(&data).is_ok_and(|(header, _)| new.is_own_header(&header)) {
+            continue;
+        }
 
-    if fs::write(&output_path, &decompressed_data).is_ok() {
-        if let Err(e) = secure_delete(file_path, skip_dod) {
-            eprintln!("Error securely deleting file {}: {}", file_path.display(), e);
+        let name_key = match NameKey::open(&data, old) {
+            Ok(name_key) => name_key,
+            Err(LockitError::WrongKey(_)) => continue,
+            Err(e) => return Err(e),
+        };
+        if rekeyed.contains(&name_key) && !whole_directory {
+            continue;
         }
-        Some(output_path)
-    } else {
-        eprintln!("Error writing decompressed file {}", output_path.display());
-        None
+
+        let sealed = name_key.seal(new)?;
+        let target = if whole_directory { key_path.clone() } else { name_key_path(directory, name_key_paths(directory).len() as u32) };
+        write_atomically(&target, |file| Ok(file.write_all(&sealed)?), |_, ()| Ok(()))?;
+        rekeyed.push(name_key);
     }
+    Ok(())
+}
+
+fn extension() -> &'static str {
+    &config::get().extension.value
+}
+
+fn directory_extension() -> &'static str {
+    &config::get().directory_extension.value
 }
 
 pub fn secure_delete(path: &Path, skip_dod: bool) -> io::Result<()> {
@@ -284,37 +1079,24 @@ pub fn secure_delete(path: &Path, skip_dod: bool) -> io::Result<()> {
             return Ok(());
         }
 
-        let metadata = fs::metadata(&path)?;
+        let metadata = fs::metadata(path)?;
         let file_size = metadata.len();
 
         let mut file = OpenOptions::new()
             .write(true)
             .read(true)
-            .open(&path)?;
-
-        fn verify_pass(file: &mut std::fs::File, expected: &[u8]) -> io::Result<bool> {
-            let mut buffer = vec![0u8; expected.len()];
-            file.seek(SeekFrom::Start(0))?;
-            file.read_exact(&mut buffer)?;
-            Ok(buffer == expected)
-        }
-
-        let pass1 = vec![0xFF; file_size as usize];
-        file.write_all(&pass1)?;
-        file.sync_all()?;
+            .open(path)?;
 
-        file.seek(SeekFrom::Start(0))?;
-        let pass2 = vec![0x00; file_size as usize];
-        file.write_all(&pass2)?;
-        file.sync_all()?;
+        overwrite_pass(&mut file, file_size, |buffer| buffer.fill(0xFF))?;
+        overwrite_pass(&mut file, file_size, |buffer| buffer.fill(0x00))?;
 
-        file.seek(SeekFrom::Start(0))?;
-        let random_data: Vec<u8> = (0..file_size).map(|_| rand::random::<u8>()).collect();
-        file.write_all(&random_data)?;
-        file.sync_all()?;
+        // The random pass is generated from a seed so it can be verified without keeping it in memory
+        let seed: [u8; 32] = rand::random();
+        let mut rng = StdRng::from_seed(seed);
+        overwrite_pass(&mut file, file_size, |buffer| rng.fill_bytes(buffer))?;
 
-        if !verify_pass(&mut file, &random_data)? {
-            return Err(io::Error::new(io::ErrorKind::Other, "Verification failed at pass 3"));
+        if !verify_pass(&mut file, file_size, seed)? {
+            return Err(io::Error::other("Verification failed at pass 3"));
         }
 
         drop(file);
@@ -324,6 +1106,39 @@ pub fn secure_delete(path: &Path, skip_dod: bool) -> io::Result<()> {
     Ok(())
 }
 
+fn overwrite_pass(file: &mut File, file_size: u64, mut fill: impl FnMut(&mut [u8])) -> io::Result<()> {
+    let mut buffer = vec![0u8; WIPE_BUFFER_SIZE];
+    let mut remaining = file_size;
+
+    file.seek(SeekFrom::Start(0))?;
+    while remaining > 0 {
+        let len = remaining.min(WIPE_BUFFER_SIZE as u64) as usize;
+        fill(&mut buffer[..len]);
+        file.write_all(&buffer[..len])?;
+        remaining -= len as u64;
+    }
+    file.sync_all()
+}
+
+fn verify_pass(file: &mut File, file_size: u64, seed: [u8; 32]) -> io::Result<bool> {
+    let mut rng = StdRng::from_seed(seed);
+    let mut expected = vec![0u8; WIPE_BUFFER_SIZE];
+    let mut actual = vec![0u8; WIPE_BUFFER_SIZE];
+    let mut remaining = file_size;
+
+    file.seek(SeekFrom::Start(0))?;
+    while remaining > 0 {
+        let len = remaining.min(WIPE_BUFFER_SIZE as u64) as usize;
+        rng.fill_bytes(&mut expected[..len]);
+        file.read_exact(&mut actual[..len])?;
+        if expected[..len] != actual[..len] {
+            return Ok(false);
+        }
+        remaining -= len as u64;
+    }
+    Ok(true)
+}
+
 pub fn secure_delete_directory(directory_path: &Path, skip_dod: bool) -> io::Result<()> {
     for entry in fs::read_dir(directory_path)? {
         let entry = entry?;
@@ -338,3 +1153,220 @@ pub fn secure_delete_directory(directory_path: &Path, skip_dod: bool) -> io::Res
 
     fs::remove_dir(directory_path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use std::os::unix::ffi::OsStrExt;
+
+    #[cfg(unix)]
+    const NON_UTF8_NAME: &[u8] = b"bad\xff\xfe";
+
+    fn flags(encrypt: bool, encrypt_filenames: bool, dir_mode: bool) -> Flags<'static> {
+        Flags { encrypt, encrypt_filenames, dir_mode, skip_dod: true, force: false, keep: false, dry_run: false, output_dir: None }
+    }
+
+    fn run(path: &Path, keyring: &Keyring, flags: &Flags) {
+        let mut report = Report::default();
+        if path.is_dir() {
+            process_directory_with_flags(path, keyring, flags, &mut report);
+        } else {
+            process_file_with_flags(path, keyring, flags, &mut report);
+        }
+        assert!(report.failures.is_empty(), "{:?}", report.failures);
+        assert!(report.skipped.is_empty(), "{:?}", report.skipped);
+    }
+
+    fn directories(directory: &Path) -> Vec<PathBuf> {
+        fs::read_dir(directory).unwrap().map(|entry| entry.unwrap().path()).filter(|path| path.is_dir()).collect()
+    }
+
+    fn encrypted_files(directory: &Path) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        for path in fs::read_dir(directory).unwrap().map(|entry| entry.unwrap().path()) {
+            if path.is_dir() {
+                files.extend(encrypted_files(&path));
+            } else if !is_name_file(&path) {
+                files.push(path);
+            }
+        }
+        files
+    }
+
+    fn only_entry(directory: &Path) -> PathBuf {
+        let entries: Vec<PathBuf> = fs::read_dir(directory).unwrap().map(|entry| entry.unwrap().path()).collect();
+        assert_eq!(entries.len(), 1, "{:?}", entries);
+        entries.into_iter().next().unwrap()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn archive_with_non_utf8_entry_round_trips() {
+        let temp = tempfile::tempdir().unwrap();
+        let tree = temp.path().join("tree");
+        fs::create_dir_all(tree.join("sub")).unwrap();
+        fs::write(tree.join(OsStr::from_bytes(NON_UTF8_NAME)), b"raw name").unwrap();
+        fs::write(tree.join("sub").join(OsStr::from_bytes(NON_UTF8_NAME)), b"nested").unwrap();
+        let keyring = Keyring::new(None).with_key([0x42; 32]);
+
+        run(&tree, &keyring, &flags(true, false, true));
+        let archive = only_entry(temp.path());
+        assert!(archive.is_file());
+
+        run(&archive, &keyring, &flags(false, false, false));
+        assert_eq!(only_entry(temp.path()), tree);
+        assert_eq!(fs::read(tree.join(OsStr::from_bytes(NON_UTF8_NAME))).unwrap(), b"raw name");
+        assert_eq!(fs::read(tree.join("sub").join(OsStr::from_bytes(NON_UTF8_NAME))).unwrap(), b"nested");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn encrypted_non_utf8_names_round_trip() {
+        let temp = tempfile::tempdir().unwrap();
+        let tree = temp.path().join("tree");
+        fs::create_dir(&tree).unwrap();
+        fs::write(tree.join(OsStr::from_bytes(NON_UTF8_NAME)), b"raw name").unwrap();
+        let keyring = Keyring::new(None).with_key([0x42; 32]);
+
+        run(&tree, &keyring, &flags(true, true, false));
+        assert!(!tree.exists());
+
+        let encrypted = fs::read_dir(temp.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.is_dir())
+            .unwrap();
+        run(&encrypted, &keyring, &flags(false, true, false));
+        assert_eq!(fs::read(tree.join(OsStr::from_bytes(NON_UTF8_NAME))).unwrap(), b"raw name");
+        assert_eq!(fs::read_dir(&tree).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn rekeyed_tree_opens_with_the_new_key_only() {
+        let temp = tempfile::tempdir().unwrap();
+        let tree = temp.path().join("tree");
+        fs::create_dir_all(tree.join("sub")).unwrap();
+        fs::write(tree.join("a.txt"), b"first").unwrap();
+        fs::write(tree.join("sub").join("b.txt"), b"second").unwrap();
+        let (old, new) = (Keyring::new(None).with_key([1; 32]), Keyring::new(None).with_key([2; 32]));
+
+        run(&tree, &old, &flags(true, true, false));
+        let encrypted = directories(temp.path()).pop().unwrap();
+        let mut report = Report::default();
+        rekey_path(&encrypted, &old, &new, true, &mut report);
+        assert!(report.failures.is_empty(), "{:?}", report.failures);
+
+        for file in encrypted_files(&encrypted) {
+            assert!(matches!(decrypt_to_writer(&file, &old, io::sink()), Err(LockitError::WrongKey(_))));
+        }
+        run(&encrypted, &new, &flags(false, true, false));
+        assert_eq!(fs::read(tree.join("a.txt")).unwrap(), b"first");
+        assert_eq!(fs::read(tree.join("sub").join("b.txt")).unwrap(), b"second");
+    }
+
+    #[test]
+    fn rekeying_one_entry_leaves_its_siblings_to_the_old_key() {
+        let temp = tempfile::tempdir().unwrap();
+        for name in ["a", "b"] {
+            fs::create_dir(temp.path().join(name)).unwrap();
+            fs::write(temp.path().join(name).join("file.txt"), name).unwrap();
+        }
+        let (old, new) = (Keyring::new(None).with_key([1; 32]), Keyring::new(None).with_key([2; 32]));
+
+        run(&temp.path().join("a"), &old, &flags(true, true, false));
+        let encrypted_a = directories(temp.path()).into_iter().find(|path| !path.ends_with("b")).unwrap();
+        run(&temp.path().join("b"), &old, &flags(true, true, false));
+        let encrypted_b = directories(temp.path()).into_iter().find(|path| *path != encrypted_a).unwrap();
+
+        let mut report = Report::default();
+        rekey_path(&encrypted_a, &old, &new, true, &mut report);
+        assert!(report.failures.is_empty(), "{:?}", report.failures);
+        assert_eq!(name_key_paths(temp.path()).len(), 2);
+
+        run(&encrypted_b, &old, &flags(false, true, false));
+        run(&encrypted_a, &new, &flags(false, true, false));
+        assert_eq!(fs::read(temp.path().join("a").join("file.txt")).unwrap(), b"a");
+        assert_eq!(fs::read(temp.path().join("b").join("file.txt")).unwrap(), b"b");
+    }
+
+    #[test]
+    fn named_files_are_verified_whatever_their_extension() {
+        let temp = tempfile::tempdir().unwrap();
+        let keyring = Keyring::new(None).with_key([0x42; 32]);
+        fs::write(temp.path().join("plain.txt"), b"data").unwrap();
+        run(&temp.path().join("plain.txt"), &keyring, &flags(true, false, false));
+        let renamed = temp.path().join("renamed");
+        fs::rename(only_entry(temp.path()), &renamed).unwrap();
+
+        let mut report = Report::default();
+        verify_path(&renamed, &keyring, &mut report);
+        assert_eq!((report.succeeded, report.skipped.len()), (1, 0));
+
+        let mut report = Report::default();
+        verify_path(temp.path(), &keyring, &mut report);
+        assert_eq!((report.succeeded, report.skipped.len()), (0, 1));
+    }
+
+    #[test]
+    fn conflict_policies_resolve_existing_outputs() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("a.txt");
+        let policies = [ConflictPolicy::Fail, ConflictPolicy::Skip, ConflictPolicy::Rename, ConflictPolicy::Overwrite];
+        for policy in policies {
+            assert_eq!(resolve_output_with(&path, policy).unwrap(), Some(path.clone()));
+        }
+
+        fs::write(&path, b"existing").unwrap();
+        assert!(matches!(
+            resolve_output_with(&path, ConflictPolicy::Fail),
+            Err(LockitError::Io(e)) if e.kind() == io::ErrorKind::AlreadyExists
+        ));
+        assert_eq!(resolve_output_with(&path, ConflictPolicy::Skip).unwrap(), None);
+        assert_eq!(resolve_output_with(&path, ConflictPolicy::Overwrite).unwrap(), Some(path.clone()));
+        assert_eq!(resolve_output_with(&path, ConflictPolicy::Rename).unwrap(), Some(temp.path().join("a (1).txt")));
+        fs::write(temp.path().join("a (1).txt"), b"existing").unwrap();
+        assert_eq!(resolve_output_with(&path, ConflictPolicy::Rename).unwrap(), Some(temp.path().join("a (2).txt")));
+    }
+
+    #[test]
+    fn numbers_go_before_the_first_extension() {
+        let cases = [
+            ("plain", "plain (1)"),
+            ("a.txt", "a (1).txt"),
+            ("a.tar.gz.lockit", "a (1).tar.gz.lockit"),
+            (".bashrc", ".bashrc (1)"),
+            (".config.tar.gz", ".config (1).tar.gz"),
+        ];
+        for (name, numbered) in cases {
+            assert_eq!(numbered_path(&Path::new("dir").join(name), 1).unwrap(), Path::new("dir").join(numbered));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_stems_are_numbered() {
+        let name = [NON_UTF8_NAME, b".txt"].concat();
+        let numbered = [NON_UTF8_NAME, b" (3).txt"].concat();
+        assert_eq!(
+            numbered_path(Path::new(OsStr::from_bytes(&name)), 3).unwrap(),
+            Path::new(OsStr::from_bytes(&numbered))
+        );
+    }
+
+    #[test]
+    fn overwriting_replaces_an_existing_directory_as_a_whole() {
+        let temp = tempfile::tempdir().unwrap();
+        let directory = temp.path().join("output");
+        fs::create_dir_all(directory.join("sub")).unwrap();
+        fs::write(directory.join("sub").join("file"), b"old").unwrap();
+        remove_existing(&directory).unwrap();
+        assert!(!exists(&directory));
+
+        let file = temp.path().join("file");
+        fs::write(&file, b"old").unwrap();
+        remove_existing(&file).unwrap();
+        assert!(!exists(&file));
+        remove_existing(&file).unwrap();
+    }
+}
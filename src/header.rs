@@ -0,0 +1,436 @@
+use std::io;
+
+pub const MAGIC: &[u8; 6] = b"LOCKIT";
+pub const FORMAT_VERSION: u8 = 2;
+
+// Oldest versioned header still read, under the same rules as the current one. Rekeyed files keep
+// their version, as it is part of the associated data.
+pub const MIN_VERSION: u8 = 1;
+
+// Version reported for headerless files written before the container format existed
+pub const LEGACY_VERSION: u8 = 0;
+
+pub const FLAG_ARCHIVE: u8 = 0x01;
+pub const FLAG_ENCRYPTED_NAME: u8 = 0x02;
+
+// Extension records with this bit set must be understood by the reader
+const CRITICAL_EXTENSION: u8 = 0x80;
+
+// Payload is a sequence of AEAD-sealed chunks of the given plaintext size (u32)
+pub const EXT_CHUNK_SIZE: u8 = 0x81;
+// Payload is a sequence of length-prefixed records of at most the given sealed size (u32), see pipe.rs
+pub const EXT_RECORDS: u8 = 0x82;
+
+// File key wrapped for one X25519 recipient, see recipient.rs; repeated once per recipient
+pub const EXT_RECIPIENT: u8 = 0x83;
+
+// Random per-file data key wrapped under the key encryption key: nonce (12) || sealed key (48)
+pub const EXT_WRAPPED_KEY: u8 = 0x84;
+
+// HMAC-SHA256 of a fixed string under the content key, so the payload only opens under one key
+pub const EXT_KEY_COMMITMENT: u8 = 0x85;
+
+// Decompressed payload starts with a metadata block (original name, permissions, times), see metadata.rs.
+// Critical, so older readers refuse the file instead of writing the block into the output.
+pub const EXT_METADATA: u8 = 0x86;
+
+const KNOWN_CRITICAL_EXTENSIONS: &[u8] =
+    &[EXT_CHUNK_SIZE, EXT_RECORDS, EXT_RECIPIENT, EXT_WRAPPED_KEY, EXT_KEY_COMMITMENT, EXT_METADATA];
+
+// Records that only describe how the data key is protected; `rekey` replaces them
+const KEY_EXTENSIONS: &[u8] = &[EXT_RECIPIENT, EXT_WRAPPED_KEY];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cipher {
+    Aes256Gcm,
+    XChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub fn id(self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 1,
+            Cipher::XChaCha20Poly1305 => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> io::Result<Self> {
+        match id {
+            1 => Ok(Cipher::Aes256Gcm),
+            2 => Ok(Cipher::XChaCha20Poly1305),
+            _ => Err(unsupported(format!("Unsupported cipher id {}", id))),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Cipher::Aes256Gcm => "aes-256-gcm",
+            Cipher::XChaCha20Poly1305 => "xchacha20-poly1305",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Cipher::Aes256Gcm, Cipher::XChaCha20Poly1305].into_iter().find(|cipher| cipher.name() == name)
+    }
+
+    pub fn nonce_size(self) -> usize {
+        match self {
+            Cipher::Aes256Gcm => 12,
+            Cipher::XChaCha20Poly1305 => 24,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kdf {
+    HkdfSha256,
+    Argon2id,
+    X25519,
+    RawKey,
+}
+
+impl Kdf {
+    pub fn id(self) -> u8 {
+        match self {
+            Kdf::HkdfSha256 => 1,
+            Kdf::Argon2id => 2,
+            Kdf::X25519 => 3,
+            Kdf::RawKey => 4,
+        }
+    }
+
+    pub fn from_id(id: u8) -> io::Result<Self> {
+        match id {
+            1 => Ok(Kdf::HkdfSha256),
+            2 => Ok(Kdf::Argon2id),
+            3 => Ok(Kdf::X25519),
+            4 => Ok(Kdf::RawKey),
+            _ => Err(unsupported(format!("Unsupported KDF id {}", id))),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kdf::HkdfSha256 => "hkdf-sha256",
+            Kdf::Argon2id => "argon2id",
+            Kdf::X25519 => "x25519",
+            Kdf::RawKey => "key-file",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Zstd,
+}
+
+impl Compression {
+    pub fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> io::Result<Self> {
+        match id {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Zstd),
+            _ => Err(unsupported(format!("Unsupported compression codec id {}", id))),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Zstd => "zstd",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub cipher: Cipher,
+    pub kdf: Kdf,
+    pub kdf_params: Vec<u8>,
+    pub compression: Compression,
+    pub flags: u8,
+    pub nonce: Vec<u8>,
+    pub extensions: Vec<(u8, Vec<u8>)>,
+}
+
+impl Header {
+    pub fn extension(&self, tag: u8) -> Option<&[u8]> {
+        self.extensions
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    pub fn chunk_size(&self) -> Option<u32> {
+        self.extension(EXT_CHUNK_SIZE)
+            .map(|value| u32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+    }
+
+    pub fn record_size_limit(&self) -> Option<u32> {
+        self.extension(EXT_RECORDS)
+            .map(|value| u32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+    }
+
+    pub fn recipient_stanzas(&self) -> Vec<&[u8]> {
+        self.extensions
+            .iter()
+            .filter(|(tag, _)| *tag == EXT_RECIPIENT)
+            .map(|(_, value)| value.as_slice())
+            .collect()
+    }
+
+    pub fn is_key_extension(tag: u8) -> bool {
+        KEY_EXTENSIONS.contains(&tag)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_fields(self.kdf.id(), &self.kdf_params, |_| true)
+    }
+
+    // Bytes authenticated as AEAD associated data. Enveloped files leave out the key encapsulation
+    // (KDF, its parameters and the key records), so `rekey` can replace it without touching the
+    // payload; the wrapped data key is authenticated by its own AEAD tag instead.
+    pub fn associated_data(&self, encoded: &[u8]) -> Vec<u8> {
+        if self.extension(EXT_WRAPPED_KEY).is_none() {
+            return encoded.to_vec();
+        }
+        self.encode_fields(0, &[], |tag| !Header::is_key_extension(tag))
+    }
+
+    fn encode_fields(&self, kdf_id: u8, kdf_params: &[u8], include: impl Fn(u8) -> bool) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.push(self.version);
+        // Header length, patched once the variable-size fields are written
+        out.extend_from_slice(&[0, 0]);
+        out.push(self.cipher.id());
+        out.push(kdf_id);
+        out.push(self.compression.id());
+        out.push(self.flags);
+        out.push(kdf_params.len() as u8);
+        out.extend_from_slice(kdf_params);
+        out.push(self.nonce.len() as u8);
+        out.extend_from_slice(&self.nonce);
+        for (tag, value) in self.extensions.iter().filter(|(tag, _)| include(*tag)) {
+            out.push(*tag);
+            out.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            out.extend_from_slice(value);
+        }
+
+        let len = out.len() as u16;
+        out[7..9].copy_from_slice(&len.to_le_bytes());
+        out
+    }
+}
+
+pub fn has_magic(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+// Length of the fixed prefix that has to be read before the full header length is known
+pub const PREFIX_LEN: usize = 9;
+
+pub fn encoded_len(prefix: &[u8]) -> usize {
+    u16::from_le_bytes([prefix[7], prefix[8]]) as usize
+}
+
+// Parses a versioned header and returns it together with its encoded length,
+// which is also the offset of the payload.
+pub fn parse(data: &[u8]) -> io::Result<(Header, usize)> {
+    if !has_magic(data) {
+        return Err(invalid("Missing lockit header magic"));
+    }
+
+    let mut reader = ByteReader::new(data, MAGIC.len());
+    let version = reader.u8()?;
+    if !(MIN_VERSION..=FORMAT_VERSION).contains(&version) {
+        return Err(unsupported(format!("Unsupported format version {}", version)));
+    }
+
+    let header_len = reader.u16()? as usize;
+    if header_len < PREFIX_LEN || header_len > data.len() {
+        return Err(invalid("Truncated lockit header"));
+    }
+    let mut reader = ByteReader::new(&data[..header_len], reader.pos);
+
+    let cipher = Cipher::from_id(reader.u8()?)?;
+    let kdf = Kdf::from_id(reader.u8()?)?;
+    // Passphrases used as HKDF input are only read from headerless files
+    if kdf == Kdf::HkdfSha256 {
+        return Err(unsupported("KDF id 1 is not valid in a versioned header".to_string()));
+    }
+    let compression = Compression::from_id(reader.u8()?)?;
+    let flags = reader.u8()?;
+    let kdf_params_len = reader.u8()? as usize;
+    let kdf_params = reader.bytes(kdf_params_len)?.to_vec();
+    let nonce_len = reader.u8()? as usize;
+    let nonce = reader.bytes(nonce_len)?.to_vec();
+
+    let mut extensions = Vec::new();
+    while !reader.is_empty() {
+        let tag = reader.u8()?;
+        let len = reader.u16()? as usize;
+        let value = reader.bytes(len)?.to_vec();
+        if tag & CRITICAL_EXTENSION != 0 && !KNOWN_CRITICAL_EXTENSIONS.contains(&tag) {
+            return Err(unsupported(format!("Unsupported critical header extension {:#04x}", tag)));
+        }
+        if (tag == EXT_CHUNK_SIZE || tag == EXT_RECORDS) && value.len() != 4 {
+            return Err(invalid("Invalid chunk size record"));
+        }
+        extensions.push((tag, value));
+    }
+
+    let header = Header {
+        version,
+        cipher,
+        kdf,
+        kdf_params,
+        compression,
+        flags,
+        nonce,
+        extensions,
+    };
+    Ok((header, header_len))
+}
+
+struct ByteReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        ByteReader { data, pos }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let end = self.pos.checked_add(len).filter(|&end| end <= self.data.len());
+        match end {
+            Some(end) => {
+                let slice = &self.data[self.pos..end];
+                self.pos = end;
+                Ok(slice)
+            }
+            None => Err(invalid("Truncated lockit header")),
+        }
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn unsupported(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Header {
+        Header {
+            version: FORMAT_VERSION,
+            cipher: Cipher::XChaCha20Poly1305,
+            kdf: Kdf::Argon2id,
+            kdf_params: (0..28).collect(),
+            compression: Compression::Zstd,
+            flags: FLAG_ARCHIVE | FLAG_ENCRYPTED_NAME,
+            nonce: vec![7; 19],
+            extensions: vec![(EXT_CHUNK_SIZE, 65536u32.to_le_bytes().to_vec()), (EXT_KEY_COMMITMENT, vec![9; 32]), (0x10, b"skipped".to_vec())],
+        }
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let header = sample();
+        let mut encoded = header.encode();
+        assert_eq!(&encoded[..7], b"LOCKIT\x02");
+        assert_eq!(encoded_len(&encoded), encoded.len());
+
+        encoded.extend_from_slice(b"payload");
+        let (parsed, len) = parse(&encoded).unwrap();
+        assert_eq!(parsed, header);
+        assert_eq!(&encoded[len..], b"payload");
+        assert_eq!(parsed.chunk_size(), Some(65536));
+    }
+
+    #[test]
+    fn version_1_headers_keep_their_version() {
+        let header = Header { version: MIN_VERSION, ..sample() };
+        let encoded = header.encode();
+        assert_eq!(encoded[6], MIN_VERSION);
+        assert_eq!(parse(&encoded).unwrap().0, header);
+    }
+
+    #[test]
+    fn unknown_versions_are_unsupported() {
+        for version in [0, FORMAT_VERSION + 1] {
+            let mut encoded = sample().encode();
+            encoded[6] = version;
+            assert_eq!(parse(&encoded).unwrap_err().kind(), io::ErrorKind::Unsupported);
+        }
+    }
+
+    #[test]
+    fn hkdf_passphrases_are_unsupported() {
+        let header = Header { kdf: Kdf::HkdfSha256, kdf_params: vec![0; 16], ..sample() };
+        assert_eq!(parse(&header.encode()).unwrap_err().kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn unknown_critical_extensions_are_unsupported() {
+        let header = Header { extensions: vec![(0xf0, Vec::new())], ..sample() };
+        assert_eq!(parse(&header.encode()).unwrap_err().kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn malformed_headers_are_rejected() {
+        let encoded = sample().encode();
+        // Cut inside the header, a header length beyond the input, and a bad chunk size record
+        assert_eq!(parse(&encoded[..encoded.len() - 1]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let mut too_long = encoded.clone();
+        too_long[7..9].copy_from_slice(&u16::MAX.to_le_bytes());
+        assert_eq!(parse(&too_long).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let header = Header { extensions: vec![(EXT_CHUNK_SIZE, vec![1, 2])], ..sample() };
+        assert_eq!(parse(&header.encode()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(parse(b"LOCKET\x02\x09\x00").unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn associated_data_leaves_out_key_records() {
+        let mut header = sample();
+        // Without a wrapped data key the whole header is authenticated
+        assert_eq!(header.associated_data(&header.encode()), header.encode());
+
+        header.extensions.push((EXT_WRAPPED_KEY, vec![1; 60]));
+        let aad = header.associated_data(&header.encode());
+        let mut rekeyed = header.clone();
+        rekeyed.kdf = Kdf::RawKey;
+        rekeyed.kdf_params = vec![2; 8];
+        rekeyed.extensions.last_mut().unwrap().1 = vec![3; 60];
+        assert_eq!(rekeyed.associated_data(&rekeyed.encode()), aad);
+        assert_ne!(rekeyed.encode(), header.encode());
+    }
+}
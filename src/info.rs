@@ -0,0 +1,154 @@
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use crate::config;
+use crate::crypto;
+use crate::error::{LockitError, Result};
+use crate::file_operations::is_stdio;
+use crate::header::{self, Header, Kdf, FLAG_ARCHIVE, FLAG_ENCRYPTED_NAME};
+use crate::recipient;
+
+// What the header of an encrypted file tells without a key. Headerless files written before the
+// container format only have a format version and a size.
+#[derive(Serialize)]
+pub struct Info {
+    pub path: String,
+    pub size: u64,
+    pub format_version: u8,
+    pub contents: Option<&'static str>,
+    pub cipher: Option<&'static str>,
+    pub kdf: Option<&'static str>,
+    pub argon2: Option<Argon2Costs>,
+    pub key_file_id: Option<String>,
+    pub recipients: Vec<String>,
+    pub compression: Option<&'static str>,
+    pub chunk_size: Option<u32>,
+    pub record_size_limit: Option<u32>,
+    pub encrypted_name: Option<bool>,
+    pub metadata: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct Argon2Costs {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Info {
+    // Standard input, given as `-`, is read to the end to learn its size
+    pub fn read(path: &Path) -> Result<Self> {
+        let (size, header) = if is_stdio(path) {
+            let mut input = io::stdin().lock();
+            let (consumed, header) = crypto::read_header(&mut input)?;
+            (consumed.len() as u64 + io::copy(&mut input, &mut io::sink())?, header)
+        } else {
+            (fs::metadata(path)?.len(), crypto::read_header(&mut BufReader::new(File::open(path)?))?.1)
+        };
+
+        let mut info = Info {
+            path: path.display().to_string(),
+            size,
+            format_version: header::LEGACY_VERSION,
+            contents: None,
+            cipher: None,
+            kdf: None,
+            argon2: None,
+            key_file_id: None,
+            recipients: Vec::new(),
+            compression: None,
+            chunk_size: None,
+            record_size_limit: None,
+            encrypted_name: None,
+            metadata: None,
+        };
+        match header {
+            Some(header) => info.describe(&header),
+            // Headerless files are only recognized by their extension
+            None if path.extension().and_then(|ext| ext.to_str()) != Some(&config::get().extension.value) => {
+                return Err(LockitError::Unsupported("Not encrypted by lockit, the file has no lockit header".into()));
+            }
+            None => {}
+        }
+        Ok(info)
+    }
+
+    fn describe(&mut self, header: &Header) {
+        self.format_version = header.version;
+        self.contents = Some(if header.flags & FLAG_ARCHIVE != 0 {
+            "archive"
+        } else if header.extension(header::EXT_RECORDS).is_some() {
+            "pipe stream"
+        } else {
+            "file"
+        });
+        self.cipher = Some(header.cipher.name());
+        self.kdf = Some(header.kdf.name());
+        self.compression = Some(header.compression.name());
+        self.chunk_size = header.chunk_size();
+        self.record_size_limit = header.record_size_limit();
+        self.encrypted_name = Some(header.flags & FLAG_ENCRYPTED_NAME != 0);
+        self.metadata = Some(header.extension(header::EXT_METADATA).is_some());
+
+        match header.kdf {
+            Kdf::Argon2id => {
+                self.argon2 = crypto::argon2_costs(&header.kdf_params)
+                    .map(|(memory_kib, iterations, parallelism)| Argon2Costs { memory_kib, iterations, parallelism })
+            }
+            Kdf::RawKey => self.key_file_id = Some(hex::encode(&header.kdf_params)),
+            Kdf::X25519 => {
+                self.recipients = header.recipient_stanzas().into_iter().filter_map(recipient::stanza_key_id).map(hex::encode).collect()
+            }
+            Kdf::HkdfSha256 => {}
+        }
+    }
+
+    pub fn print(&self) {
+        println!("{}", self.path);
+        print_field("Size", format!("{} bytes", self.size));
+        if self.cipher.is_none() {
+            print_field("Format version", format!("{} (headerless, written before the container format)", self.format_version));
+            return;
+        }
+
+        print_field("Format version", self.format_version.to_string());
+        let contents = match self.contents {
+            Some("archive") => "directory archive (--zipdir)",
+            Some("pipe stream") => "pipe stream (--pipe)",
+            _ => "single file",
+        };
+        print_field("Contents", contents.to_string());
+        print_field("Cipher", self.cipher.unwrap_or_default().to_string());
+        print_field("Key derivation", self.kdf.unwrap_or_default().to_string());
+        if let Some(costs) = &self.argon2 {
+            print_field(
+                "Argon2id costs",
+                format!("{} KiB memory, {} iterations, parallelism {}", costs.memory_kib, costs.iterations, costs.parallelism),
+            );
+        }
+        if let Some(key_id) = &self.key_file_id {
+            print_field("Key file id", key_id.clone());
+        }
+        if !self.recipients.is_empty() {
+            print_field("Recipients", self.recipients.join(", "));
+        }
+        print_field("Compression", self.compression.unwrap_or_default().to_string());
+        match (self.chunk_size, self.record_size_limit) {
+            (Some(chunk_size), _) => print_field("Chunk size", format!("{} bytes", chunk_size)),
+            (None, Some(limit)) => print_field("Records", format!("up to {} bytes each", limit)),
+            (None, None) => print_field("Chunk size", "single message".to_string()),
+        }
+        print_field("Encrypted name", yes_no(self.encrypted_name));
+        print_field("Metadata block", yes_no(self.metadata));
+    }
+}
+
+fn print_field(label: &str, value: String) {
+    println!("    {:<16} {}", format!("{}:", label), value);
+}
+
+fn yes_no(value: Option<bool>) -> String {
+    if value == Some(true) { "yes" } else { "no" }.to_string()
+}
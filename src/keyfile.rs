@@ -0,0 +1,55 @@
+use aes_gcm::aead::OsRng;
+use hkdf::Hkdf;
+use rand::RngCore;
+use sha2::Sha256;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const KEY_PREFIX: &str = "LOCKIT-KEY-";
+pub const KEY_ID_SIZE: usize = 8;
+
+const KEY_ID_INFO: &[u8] = b"lockit key id";
+
+pub fn generate_key() -> [u8; 32] {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+pub fn encode_key(key: &[u8; 32]) -> String {
+    format!(
+        "# created by lockit keygen --symmetric\n# key id: {}\n{}{}\n",
+        hex::encode(key_id(key)),
+        KEY_PREFIX,
+        hex::encode(key)
+    )
+}
+
+// Key files hold a single `LOCKIT-KEY-<hex>` line, with blank lines and `#` comments ignored.
+// A file of exactly 32 bytes is used as a raw key.
+pub fn read_key_file(path: &Path) -> io::Result<[u8; 32]> {
+    let contents = fs::read(path)?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("No valid key found in {}", path.display()));
+
+    let encoded = std::str::from_utf8(&contents).ok().and_then(|text| {
+        text.lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .and_then(|line| line.strip_prefix(KEY_PREFIX))
+            .and_then(|key| hex::decode(key).ok())
+    });
+
+    match encoded {
+        Some(key) => key.try_into().map_err(|_| invalid()),
+        None => contents.try_into().map_err(|_| invalid()),
+    }
+}
+
+// Identifies the key in file headers without revealing anything about it
+pub fn key_id(key: &[u8; 32]) -> [u8; KEY_ID_SIZE] {
+    let hkdf = Hkdf::<Sha256>::new(None, key);
+    let mut id = [0u8; KEY_ID_SIZE];
+    hkdf.expand(KEY_ID_INFO, &mut id).unwrap();
+    id
+}
@@ -1,52 +1,160 @@
-use std::{env, io, path::PathBuf};
-use std::io::{Read, Write};
-
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::{env, io};
+use x25519_dalek::PublicKey;
+
+mod config;
 mod crypto;
 mod compression;
+mod error;
 mod file_operations;
+mod header;
+mod info;
+mod keyfile;
+mod metadata;
+mod names;
+mod passphrase;
 mod pipe;
+mod plan;
+mod recipient;
 
-use file_operations::{secure_delete, secure_delete_directory};
+use error::{LockitError, Report};
+use file_operations::{is_stdio, secure_delete, secure_delete_directory, Flags};
+use passphrase::{PassphraseSource, PASSPHRASE_ENV};
 
 include!(concat!(env!("OUT_DIR"), "/default_settings.rs"));
 
+struct Options {
+    mode: Option<String>,
+    paths: Vec<PathBuf>,
+    encrypt_filenames: Option<bool>,
+    self_destruct: Option<bool>,
+    dir_mode: bool,
+    pipe_mode: bool,
+    stdout: bool,
+    force: bool,
+    keep: bool,
+    output_dir: Option<PathBuf>,
+    dry_run: bool,
+    yes: bool,
+    skip_dod: Option<bool>,
+    passphrase_source: Option<PassphraseSource>,
+    recipients: Vec<String>,
+    identities: Vec<PathBuf>,
+    key_file: Option<PathBuf>,
+    new_passphrase_file: Option<PathBuf>,
+    new_key_file: Option<PathBuf>,
+    symmetric: bool,
+    cipher: Option<String>,
+    on_conflict: Option<String>,
+    preserve_owner: Option<bool>,
+    preserve_xattrs: Option<bool>,
+    json: bool,
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    // Paths may be any bytes, so arguments are not required to be UTF-8
+    let args: Vec<OsString> = env::args_os().collect();
 
-    if args.contains(&String::from("-h")) || args.contains(&String::from("--help")) {
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
         print_help();
         return;
     }
-    
-    let (mode, paths, encrypt_filenames_flag, self_destruct_flag, dir_mode, pipe_mode, skip_dod) = parse_mode_paths_and_flags(&args);
 
-    let use_custom_password = args.contains(&String::from("-p"));
+    // Setup errors end the run right away; failures of single paths are summarized by the report
+    match run(&args) {
+        Ok(report) => {
+            if let Err(e) = report.finish() {
+                std::process::exit(e.exit_code());
+            }
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(e.exit_code());
+        }
+    }
+}
 
-    let encrypt_filenames = encrypt_filenames_flag.unwrap_or(ENCRYPT_FILENAMES);
+fn run(args: &[OsString]) -> error::Result<Report> {
+    let options = parse_mode_paths_and_flags(args).map_err(LockitError::Usage)?;
+    let settings = load_settings(&options).map_err(LockitError::Usage)?;
+    let mode = options.mode.clone().unwrap_or_else(|| settings.mode.value.clone());
+    let skip_dod = settings.skip_dod.value;
+
+    match mode.as_str() {
+        "keygen" => return process_keygen(&options.paths, options.symmetric),
+        "info" => {
+            config::init(settings);
+            return Ok(process_info(&options.paths, options.json));
+        }
+        "config" => return process_config(&options.paths, settings),
+        _ => config::init(settings),
+    }
 
     let encrypt = is_encrypt_mode(&mode);
 
-    if pipe_mode {
-        if mode != "encrypt" && mode != "decrypt" {
-            eprintln!("Error: --pipe mode can only be used with 'encrypt' or 'decrypt' modes.");
-            std::process::exit(1);
-        }
+    let encrypt_filenames = config::get().encrypt_filenames.value;
+
+    if options.pipe_mode && mode != "encrypt" && mode != "decrypt" {
+        return Err(LockitError::Usage("--pipe mode can only be used with 'encrypt' or 'decrypt' modes.".into()));
+    }
+
+    let removal = matches!(mode.as_str(), "remove" | "delete" | "rm" | "del");
+    if options.dry_run && (options.pipe_mode || options.stdout || !(removal || mode == "encrypt" || mode == "decrypt")) {
+        return Err(LockitError::Usage("--dry-run can only be used when encrypting, decrypting or removing files.".into()));
+    }
+
+    let in_place = matches!(mode.as_str(), "encrypt" | "decrypt") && !options.pipe_mode && !options.stdout && !options.keep;
+    if (removal || in_place) && !options.dry_run && !options.yes {
+        confirm_destructive(&options.paths, &mode, removal)?;
     }
 
-    let password = get_password(use_custom_password);
+    // Secure deletion needs no key, so it never asks for a passphrase
+    let keyring = if removal { crypto::Keyring::new(None) } else { build_keyring(&options, encrypt)? };
 
-    if pipe_mode {
-        process_pipe_mode(&password, encrypt);
-    } else {
-        match mode.as_str() {
-            "remove" | "delete" | "rm" | "del" => process_removal(paths, skip_dod),
-            _ => process_paths(paths, &password, encrypt, encrypt_filenames, dir_mode, skip_dod),
+    if options.pipe_mode {
+        process_pipe_mode(&keyring, encrypt)?;
+        return Ok(Report::default());
+    }
+
+    if mode == "cat" || options.stdout {
+        if !matches!(mode.as_str(), "encrypt" | "decrypt" | "cat") {
+            return Err(LockitError::Usage("--stdout can only be used with 'encrypt' or 'decrypt' modes.".into()));
         }
+        return process_stdout(&options.paths, &keyring, encrypt, options.dir_mode);
+    }
 
-        if self_destruct_flag.unwrap_or(SELF_DESTRUCT_DEFAULT) {
-            secure_self_destruct(skip_dod);
+    let report = match mode.as_str() {
+        _ if removal => process_removal(options.paths, skip_dod, options.dry_run),
+        "rekey" => process_rekey(&options, &keyring, encrypt_filenames)?,
+        "verify" => process_verify(&options.paths, &keyring),
+        _ => {
+            let output_dir = options.output_dir.as_deref().map(|path| prepare_output_dir(path, options.dry_run)).transpose()?;
+            let flags = Flags {
+                encrypt,
+                encrypt_filenames,
+                dir_mode: options.dir_mode,
+                skip_dod,
+                force: options.force,
+                keep: options.keep,
+                dry_run: options.dry_run,
+                output_dir: output_dir.as_deref(),
+            };
+            process_paths(options.paths, &keyring, &flags)
         }
+    };
+
+    if options.dry_run {
+        plan::print(&report, options.json);
+        return Ok(report);
+    }
+
+    if config::get().self_destruct.value {
+        secure_self_destruct(skip_dod);
     }
+    Ok(report)
 }
 
 fn print_help() {
@@ -56,18 +164,45 @@ fn print_help() {
 Usage:
     ./lockit <file> <file2> <dir1>             # Process files in default mode (encrypt/decrypt)
     ./lockit encrypt <file> <file2> <dir1>     # Encrypt specific files
-    ./lockit encrypt <file> -p                 # Encrypt a file with a custom passphrase
+    ./lockit encrypt <file> -p                 # Prompt for the passphrase (the default without another source)
+    ./lockit encrypt <file> --passphrase-file <path> # Read the passphrase from the first line of a file
+    ./lockit encrypt <file> --passphrase-fd <n> # Read the passphrase from an open file descriptor
+    ./lockit decrypt <file> --use-default-passphrase # Use the passphrase compiled into the binary (insecure)
     ./lockit encrypt <dir>                     # Encrypt all files in a directory
     ./lockit decrypt <dir/file>                # Decrypt a file or directory
     ./lockit encrypt <dir> --zipdir            # Compress, tar, and encrypt entire directories
+    ./lockit encrypt <file> --cipher xchacha20-poly1305 # Use XChaCha20-Poly1305 instead of AES-256-GCM
     ./lockit remove/delete/rm/del <dir/file>   # Securely delete a file / directory
     ./lockit remove <file> <file2> --skip-dod  # Skip DoD overwrite passes
     ./lockit encrypt --pipe                    # Process as pipe
-    ./lockit decrypt --pipe -p                 # Process as pipe, custom passphrase
-    ./lockit --encrypt-filenames               # Encrypt file and directory names
-    ./lockit --no-encrypt-filenames            # Keep file and directory names unchanged
-    ./lockit --self-destruct                   # Remove Lockit after use
-    ./lockit --no-self-destruct                # Retain Lockit after use
+    ./lockit cat <file>                        # Decrypt files to stdout, leaving them in place
+    ./lockit encrypt <file> --stdout           # Encrypt a file to stdout, leaving it in place
+    ./lockit decrypt - < <file>                # `-` reads from stdin and writes to stdout
+    LOCKIT_PASSPHRASE=... ./lockit decrypt --pipe # Process as pipe, passphrase from the environment
+    ./lockit keygen <identity-file>            # Generate an X25519 identity and print its public key
+    ./lockit encrypt <file> -r <public-key>    # Encrypt to one or more recipients (repeat -r)
+    ./lockit decrypt <file> -i <identity-file> # Decrypt with an identity file (repeat -i)
+    ./lockit keygen --symmetric <key-file>     # Generate a random 256-bit key file
+    ./lockit encrypt <file> --key-file <key-file> # Encrypt or decrypt with a key file instead of a passphrase
+    ./lockit rekey <dir/file>                  # Re-wrap file keys under a new passphrase
+    ./lockit rekey <dir/file> -r <public-key>  # Re-wrap file keys for new recipients
+    ./lockit rekey <dir/file> --new-passphrase-file <file> # Read the new passphrase from a file
+    ./lockit rekey <dir/file> --new-key-file <key-file>    # Re-wrap file keys under a key file
+    ./lockit verify <dir/file>                 # Check that files decrypt, without writing or deleting anything
+    ./lockit info <file> [--json]              # Show what the header of an encrypted file says, no key needed
+    ./lockit <dir> --encrypt-filenames         # Encrypt file and directory names
+    ./lockit <dir> --no-encrypt-filenames      # Keep file and directory names unchanged
+    ./lockit encrypt <dir> --force             # Also encrypt files that are already encrypted
+    ./lockit encrypt <dir> --keep              # Write the outputs, but keep the originals
+    ./lockit encrypt <dir> --output-dir <dest> # Write the outputs under another directory
+    ./lockit encrypt <dir> --dry-run [--json]  # List what would happen without changing anything
+    ./lockit encrypt <dir> --yes               # Do not ask before transforming or wiping a directory
+    ./lockit decrypt <file> --on-conflict=rename # When an output exists: fail (default), skip, rename or overwrite
+    ./lockit encrypt <file> --preserve-owner   # Also store and restore the owner and group
+    ./lockit encrypt <file> --preserve-xattrs  # Also store and restore extended attributes
+    ./lockit config show                       # Show the effective configuration and its origins
+    ./lockit <dir> --self-destruct             # Remove Lockit after use
+    ./lockit <dir> --no-self-destruct          # Retain Lockit after use
     ./lockit -h | --help                       # Show this help message
 
 Examples:
@@ -76,8 +211,13 @@ Examples:
     nc -lvnp 9999 | ./lockit decrypt --pipe
     echo "This is a very secret message" | ./lockit encrypt --pipe | nc localhost 9999
 
+Exit codes:
+    0 success, 1 I/O error, 2 usage error, 3 wrong key, 4 corrupt data,
+    5 unsupported format, 6 partial failure
+
 Mechanism:
-    Lockit compresses files using zstd and secures them with AES-256-GCM encryption.
+    Lockit compresses files using zstd and secures them with AES-256-GCM or XChaCha20-Poly1305.
+    Keys are derived from the passphrase with Argon2id.
     Provides secure file deletion following DoD 5220.22-M standard.
 
 Installation:
@@ -89,144 +229,466 @@ For more information, refer to the README.md file."#
 }
 
 
-fn parse_mode_paths_and_flags(args: &[String]) -> (String, Vec<PathBuf>, Option<bool>, Option<bool>, bool, bool, bool) {
-    let mut mode = DEFAULT_MODE.to_string();
-    let mut paths = Vec::new();
-    let mut encrypt_filenames_flag = None;
-    let mut self_destruct_flag = None;
-    let mut dir_mode = false;
-    let mut pipe_mode = false;
-    let mut skip_dod = SKIP_DOD_DEFAULT;
-
-    for arg in args.iter().skip(1) {
-        match arg.as_str() {
-            "encrypt" | "decrypt" | "remove" | "delete" | "rm" | "del" => mode = arg.clone(),
-            "--encrypt-filenames" => encrypt_filenames_flag = Some(true),
-            "--no-encrypt-filenames" => encrypt_filenames_flag = Some(false),
-            "--self-destruct" => self_destruct_flag = Some(true),
-            "--no-self-destruct" => self_destruct_flag = Some(false),
-            "--zipdir" => dir_mode = true,
-            "--pipe" => pipe_mode = true,
-            "--skip-dod" => skip_dod = true,
-            _ if !arg.starts_with('-') => paths.push(PathBuf::from(arg)),
+fn parse_mode_paths_and_flags(args: &[OsString]) -> Result<Options, String> {
+    let mut options = Options {
+        mode: None,
+        paths: Vec::new(),
+        encrypt_filenames: None,
+        self_destruct: None,
+        dir_mode: false,
+        pipe_mode: false,
+        stdout: false,
+        force: false,
+        keep: false,
+        output_dir: None,
+        dry_run: false,
+        yes: false,
+        skip_dod: None,
+        passphrase_source: None,
+        recipients: Vec::new(),
+        identities: Vec::new(),
+        key_file: None,
+        new_passphrase_file: None,
+        new_key_file: None,
+        symmetric: false,
+        cipher: None,
+        on_conflict: None,
+        preserve_owner: None,
+        preserve_xattrs: None,
+        json: false,
+    };
+
+    let mut args = args.iter().skip(1);
+    while let Some(raw_arg) = args.next() {
+        // Modes and flags are always valid UTF-8, anything else is a path
+        let Some(arg) = raw_arg.to_str() else {
+            options.paths.push(PathBuf::from(raw_arg));
+            continue;
+        };
+
+        // `--flag=value` is accepted for every flag that takes a value
+        let (arg, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag, Some(OsString::from(value))),
+            _ => (arg, None),
+        };
+        let mut value = || inline_value.clone().or_else(|| args.next().cloned());
+
+        match arg {
+            "encrypt" | "decrypt" | "remove" | "delete" | "rm" | "del" | "keygen" | "rekey" | "verify" | "info" | "cat" | "config" => {
+                options.mode = Some(arg.to_string())
+            }
+            "--encrypt-filenames" => options.encrypt_filenames = Some(true),
+            "--no-encrypt-filenames" => options.encrypt_filenames = Some(false),
+            "--self-destruct" => options.self_destruct = Some(true),
+            "--no-self-destruct" => options.self_destruct = Some(false),
+            "--zipdir" => options.dir_mode = true,
+            "--pipe" => options.pipe_mode = true,
+            "--stdout" => options.stdout = true,
+            "--force" => options.force = true,
+            "--keep" => options.keep = true,
+            "--dry-run" => options.dry_run = true,
+            "-y" | "--yes" => options.yes = true,
+            "--output-dir" => options.output_dir = Some(PathBuf::from(flag_value(arg, value())?)),
+            "--skip-dod" => options.skip_dod = Some(true),
+            "--preserve-owner" => options.preserve_owner = Some(true),
+            "--preserve-xattrs" => options.preserve_xattrs = Some(true),
+            "--json" => options.json = true,
+            "-p" => set_passphrase_source(&mut options, PassphraseSource::Prompt)?,
+            "--passphrase-file" => {
+                let path = PathBuf::from(flag_value(arg, value())?);
+                set_passphrase_source(&mut options, PassphraseSource::File(path))?
+            }
+            "--passphrase-fd" => {
+                let fd = text_flag_value(arg, value())?;
+                let fd = fd.parse().map_err(|_| format!("Invalid file descriptor: {}", fd))?;
+                set_passphrase_source(&mut options, PassphraseSource::Fd(fd))?
+            }
+            "--use-default-passphrase" => set_passphrase_source(&mut options, PassphraseSource::BuiltIn)?,
+            "-r" | "--recipient" => options.recipients.push(text_flag_value(arg, value())?),
+            "-i" | "--identity" => options.identities.push(PathBuf::from(flag_value(arg, value())?)),
+            "--key-file" => options.key_file = Some(PathBuf::from(flag_value(arg, value())?)),
+            "--new-passphrase-file" => options.new_passphrase_file = Some(PathBuf::from(flag_value(arg, value())?)),
+            "--new-key-file" => options.new_key_file = Some(PathBuf::from(flag_value(arg, value())?)),
+            "--symmetric" => options.symmetric = true,
+            "--cipher" => options.cipher = Some(text_flag_value(arg, value())?),
+            "--on-conflict" => options.on_conflict = Some(text_flag_value(arg, value())?),
+            _ if arg == "-" || !arg.starts_with('-') => options.paths.push(PathBuf::from(arg)),
             _ => {}
         }
     }
 
-    if paths.is_empty() && !pipe_mode {
-        paths.push(PathBuf::from("."));
+    let takes_paths = !matches!(options.mode.as_deref(), Some("keygen" | "config"));
+    if options.paths.is_empty() && !options.pipe_mode && takes_paths {
+        return Err("No path given. Name the files or directories to process, `.` for the current directory or `-` for standard input.".to_string());
     }
 
-    (mode, paths, encrypt_filenames_flag, self_destruct_flag, dir_mode, pipe_mode, skip_dod)
+    Ok(options)
+}
+
+// Command line flags are the last layer on top of the config files and environment
+fn load_settings(options: &Options) -> Result<config::Settings, String> {
+    let mut settings = config::load()?;
+    settings.mode.override_with(options.mode.clone().filter(|mode| mode == "encrypt" || mode == "decrypt"));
+    settings.encrypt_filenames.override_with(options.encrypt_filenames);
+    settings.self_destruct.override_with(options.self_destruct);
+    settings.skip_dod.override_with(options.skip_dod);
+    settings.cipher.override_with(options.cipher.clone());
+    settings.on_conflict.override_with(options.on_conflict.clone());
+    settings.preserve_owner.override_with(options.preserve_owner);
+    settings.preserve_xattrs.override_with(options.preserve_xattrs);
+    settings.validate()?;
+    Ok(settings)
+}
+
+fn set_passphrase_source(options: &mut Options, source: PassphraseSource) -> Result<(), String> {
+    match &options.passphrase_source {
+        Some(existing) if *existing != source => Err("Only one passphrase source can be given".to_string()),
+        _ => {
+            options.passphrase_source = Some(source);
+            Ok(())
+        }
+    }
+}
+
+fn flag_value(flag: &str, value: Option<OsString>) -> Result<OsString, String> {
+    value.ok_or_else(|| format!("{} requires a value", flag))
+}
+
+fn text_flag_value(flag: &str, value: Option<OsString>) -> Result<String, String> {
+    flag_value(flag, value)?
+        .into_string()
+        .map_err(|value| format!("Invalid value for {}: {}", flag, value.to_string_lossy()))
 }
 
 fn is_encrypt_mode(mode: &str) -> bool {
     match mode {
         "encrypt" => true,
         "decrypt" => false,
+        "rekey" => false,
+        "verify" => false,
+        "cat" => false,
         "delete" => false,
         "remove" => false,
         "rm" => false,
         "del" => false,
         _ => {
-            println!("Invalid mode: {}. Defaulting to '{}'.", mode, DEFAULT_MODE);
-            DEFAULT_MODE == "encrypt"
+            let default_mode = &config::get().mode.value;
+            println!("Invalid mode: {}. Defaulting to '{}'.", mode, default_mode);
+            default_mode == "encrypt"
         }
     }
 }
 
-fn get_password(use_custom_password: bool) -> String {
-    if use_custom_password {
-        rpassword::prompt_password("Enter the password: ").unwrap()
-    } else {
-        DEFAULT_PASSPHRASE.to_string()
+// An explicit source wins over LOCKIT_PASSPHRASE. Without either, the passphrase is prompted for,
+// unless recipients, identities or a key file already provide the keys.
+fn passphrase_source(options: &Options, encrypt: bool) -> Option<PassphraseSource> {
+    let uses_keys = options.key_file.is_some() || if encrypt { !options.recipients.is_empty() } else { !options.identities.is_empty() };
+    options.passphrase_source.clone().or_else(|| {
+        if env::var_os(PASSPHRASE_ENV).is_some() {
+            Some(PassphraseSource::Environment)
+        } else if uses_keys {
+            None
+        } else {
+            Some(PassphraseSource::Prompt)
+        }
+    })
+}
+
+fn build_keyring(options: &Options, encrypt: bool) -> error::Result<crypto::Keyring> {
+    if encrypt && options.key_file.is_some() && (!options.recipients.is_empty() || options.passphrase_source.is_some()) {
+        return Err(LockitError::Usage("--key-file cannot be combined with recipients or a passphrase".into()));
     }
+
+    if encrypt && !options.recipients.is_empty() {
+        return crypto::Keyring::new(None).with_recipients(&parse_recipients(&options.recipients)?);
+    }
+
+    let key = options.key_file.as_deref().map(keyfile::read_key_file).transpose()?;
+    if let (true, Some(key)) = (encrypt, key) {
+        return Ok(crypto::Keyring::new(None).with_key(key));
+    }
+
+    // Decrypting accepts any combination of sources, as a tree may mix them
+    let passphrase = passphrase_source(options, encrypt).map(|source| source.read(encrypt)).transpose()?;
+    let mut keyring = crypto::Keyring::new(passphrase);
+    if let Some(key) = key {
+        keyring = keyring.with_key(key);
+    }
+
+    let mut identities = Vec::new();
+    for path in &options.identities {
+        identities.extend(recipient::read_identity_file(path)?);
+    }
+    Ok(keyring.with_identities(identities))
 }
 
-fn process_pipe_mode(password: &str, encrypt: bool) {
-    let stdin = io::stdin();
-    let stdout = io::stdout();
-    let mut stdout_lock = stdout.lock();
-    let mut stdin_lock = stdin.lock();
-
-    // Use a buffer to store the read data
-    let mut buffer = [0u8; 1024]; // Buffer size of 1024 bytes
-
-    loop {
-        // Read from stdin into the buffer
-        let bytes_read = match stdin_lock.read(&mut buffer) {
-            Ok(0) => break, // EOF reached
-            Ok(n) => n,     // Number of bytes read
-            Err(e) => {
-                eprintln!("Failed to read from stdin: {}", e);
-                break;
-            }
-        };
+fn parse_recipients(encoded: &[String]) -> io::Result<Vec<PublicKey>> {
+    encoded.iter().map(|key| recipient::parse_public_key(key)).collect()
+}
 
-        // Encrypt or decrypt the input data
-        let processed_data = if encrypt {
-            pipe::encrypt_data_via_pipe(&buffer[..bytes_read], password)
+fn process_rekey(options: &Options, old: &crypto::Keyring, encrypt_filenames: bool) -> error::Result<Report> {
+    let new = rekey_keyring(options)?;
+
+    let mut report = Report::default();
+    for path in &options.paths {
+        if !path.exists() && !is_stdio(path) {
+            report.fail(path, not_found());
+            continue;
+        }
+        file_operations::rekey_path(path, old, &new, encrypt_filenames, &mut report);
+    }
+    Ok(report)
+}
+
+// The key to rekey to. The flags for the current key, such as --passphrase-file and --key-file,
+// only open the files.
+fn rekey_keyring(options: &Options) -> error::Result<crypto::Keyring> {
+    let sources = [!options.recipients.is_empty(), options.new_key_file.is_some(), options.new_passphrase_file.is_some()];
+    if sources.iter().filter(|&&given| given).count() > 1 {
+        return Err(LockitError::Usage("Only one of --recipient, --new-key-file and --new-passphrase-file can be given".into()));
+    }
+
+    if !options.recipients.is_empty() {
+        return crypto::Keyring::new(None).with_recipients(&parse_recipients(&options.recipients)?);
+    }
+    if let Some(path) = &options.new_key_file {
+        return Ok(crypto::Keyring::new(None).with_key(keyfile::read_key_file(path)?));
+    }
+    let passphrase = match &options.new_passphrase_file {
+        Some(path) => PassphraseSource::File(path.clone()).read(false)?,
+        None => passphrase::prompt("Enter the new password: ", true, "use --new-passphrase-file or --new-key-file")?,
+    };
+    Ok(crypto::Keyring::new(Some(passphrase)))
+}
+
+fn process_verify(paths: &[PathBuf], keyring: &crypto::Keyring) -> Report {
+    let mut report = Report::default();
+    for path in paths {
+        if path.exists() || is_stdio(path) {
+            file_operations::verify_path(path, keyring, &mut report);
         } else {
-            pipe::decrypt_data_via_pipe(&buffer[..bytes_read], password)
-        };
+            report.fail(path, not_found());
+        }
+    }
+    report
+}
 
-        match processed_data {
-            Ok(data) => {
-                // Write the processed data to stdout
-                if stdout_lock.write_all(&data).is_err() {
-                    eprintln!("Failed to write to stdout");
-                    break;
+// Reads headers only, so no key is asked for
+fn process_info(paths: &[PathBuf], json: bool) -> Report {
+    let mut report = Report::default();
+    let mut infos = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            report.skip(path, "is a directory, name the files to inspect");
+        } else if path.exists() || is_stdio(path) {
+            match info::Info::read(path) {
+                Ok(info) if json => {
+                    infos.push(info);
+                    report.succeeded += 1;
                 }
-                if stdout_lock.flush().is_err() {
-                    eprintln!("Failed to flush stdout");
-                    break;
+                Ok(info) => {
+                    info.print();
+                    report.succeeded += 1;
                 }
+                Err(e) => report.fail(path, e),
             }
-            Err(e) => {
-                eprintln!("Error processing data: {}", e);
-                break;
-            }
+        } else {
+            report.fail(path, not_found());
         }
     }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&infos).expect("Header information serializes to JSON"));
+    }
+    report
 }
 
+fn process_config(args: &[PathBuf], settings: config::Settings) -> error::Result<Report> {
+    if args.len() != 1 || args[0].as_os_str() != "show" {
+        return Err(LockitError::Usage("usage: lockit config show".into()));
+    }
 
-fn process_paths(paths: Vec<PathBuf>, password: &str, encrypt: bool, encrypt_filenames: bool, dir_mode: bool, skip_dod: bool) {
-    for path in paths {
-        if !path.exists() {
-            debug_print!("Invalid path: {}", path.display());
-            continue;
+    for (key, value, origin) in settings.entries() {
+        println!("{:<40} # {}", format!("{} = {}", key, value), origin);
+    }
+    Ok(Report::default())
+}
+
+fn process_keygen(paths: &[PathBuf], symmetric: bool) -> error::Result<Report> {
+    let (encoded, summary) = if symmetric {
+        let key = keyfile::generate_key();
+        (keyfile::encode_key(&key), format!("Key id: {}", hex::encode(keyfile::key_id(&key))))
+    } else {
+        let identity = recipient::generate_identity();
+        let public_key = recipient::encode_public_key(&PublicKey::from(&identity));
+        (recipient::encode_identity(&identity), format!("Public key: {}\nKey id: {}", public_key, hex::encode(recipient::key_id(&PublicKey::from(&identity)))))
+    };
+
+    let path = match paths {
+        [] => {
+            print!("{}", encoded);
+            return Ok(Report::default());
         }
+        [path] if is_stdio(path) => {
+            print!("{}", encoded);
+            return Ok(Report::default());
+        }
+        [path] => path,
+        _ => return Err(LockitError::Usage("keygen takes at most one output file.".into())),
+    };
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(encoded.as_bytes()))
+        .map_err(|e| LockitError::Io(io::Error::new(e.kind(), format!("Failed to write key file {}: {}", path.display(), e))))?;
+
+    println!("{}", summary);
+    Ok(Report::default())
+}
 
-        if path.is_file() {
-            file_operations::process_file_with_flags(&path, password, encrypt, encrypt_filenames, skip_dod);
-        } else if path.is_dir() {
-            file_operations::process_directory_with_flags(&path, password, encrypt, encrypt_filenames, dir_mode, skip_dod);
+fn process_pipe_mode(keyring: &crypto::Keyring, encrypt: bool) -> error::Result<()> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+
+    if encrypt {
+        pipe::encrypt_pipe(stdin.lock(), stdout.lock(), keyring)
+    } else {
+        pipe::decrypt_pipe(stdin.lock(), stdout.lock(), keyring)
+    }
+}
+
+// Writes to standard output and leaves the inputs in place. Decrypted files are concatenated like
+// `cat` does; ciphertexts cannot be, so encrypting takes a single path.
+fn process_stdout(paths: &[PathBuf], keyring: &crypto::Keyring, encrypt: bool, dir_mode: bool) -> error::Result<Report> {
+    if encrypt {
+        return match paths {
+            [path] if is_stdio(path) => process_pipe_mode(keyring, true).map(|()| Report::default()),
+            [path] => file_operations::encrypt_to_writer(path, keyring, dir_mode, io::stdout().lock()).map(|()| Report::default()),
+            _ => Err(LockitError::Usage("encrypt --stdout takes exactly one path.".into())),
+        };
+    }
+
+    let mut report = Report::default();
+    for path in paths {
+        if path.is_dir() {
+            report.skip(path, "is a directory");
+        } else if path.exists() || is_stdio(path) {
+            report.record(path, file_operations::decrypt_to_writer(path, keyring, io::stdout().lock()));
         } else {
-            debug_print!("Invalid path type: {}", path.display());
+            report.fail(path, not_found());
         }
     }
+    Ok(report)
 }
 
-fn process_removal(paths: Vec<PathBuf>, skip_dod: bool) {
+fn process_paths(paths: Vec<PathBuf>, keyring: &crypto::Keyring, flags: &Flags) -> Report {
+    let mut report = Report::default();
     for path in paths {
-        if !path.exists() {
-            debug_print!("Invalid path: {}", path.display());
+        if is_stdio(&path) && flags.dry_run {
+            report.skip(&path, "standard input and output are not part of a dry run");
+            continue;
+        }
+        // Decrypting tells pipe streams apart from files, as `cat -` does
+        if is_stdio(&path) {
+            let result = if flags.encrypt {
+                process_pipe_mode(keyring, true)
+            } else {
+                file_operations::decrypt_to_writer(&path, keyring, io::stdout().lock())
+            };
+            report.record(&path, result);
             continue;
         }
-
         if path.is_file() {
-            if let Err(e) = secure_delete(&path, skip_dod) {
-                eprintln!("Error securely deleting file {}: {}", path.display(), e);
-            }
+            file_operations::process_file_with_flags(&path, keyring, flags, &mut report);
         } else if path.is_dir() {
-            if let Err(e) = secure_delete_directory(&path, skip_dod) {
-                eprintln!("Error securely deleting directory {}: {}", path.display(), e);
+            file_operations::process_directory_with_flags(&path, keyring, flags, &mut report);
+        } else if path.exists() {
+            report.skip(&path, "not a file or directory");
+        } else {
+            report.fail(&path, not_found());
+            continue;
+        }
+
+        // Directories clean up their own name keys; the one next to a given path is done here
+        if !flags.encrypt && !flags.dry_run {
+            let parent = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            if let Err(e) = file_operations::remove_unused_name_key(parent) {
+                report.fail(parent, e);
+            }
+        }
+    }
+    report
+}
+
+// Asked before wiping whole directories, and before any passphrase prompt. Without a terminal to
+// ask on, the run is refused unless --yes is given.
+fn confirm_destructive(paths: &[PathBuf], mode: &str, removal: bool) -> error::Result<()> {
+    let directories: Vec<&PathBuf> = paths.iter().filter(|path| !is_stdio(path) && path.is_dir()).collect();
+    if directories.is_empty() {
+        return Ok(());
+    }
+
+    let (files, bytes) = paths
+        .iter()
+        .filter(|path| !is_stdio(path))
+        .filter_map(|path| file_operations::tree_stats(path).ok())
+        .fold((0, 0), |(files, bytes), (more_files, more_bytes)| (files + more_files, bytes + more_bytes));
+    let names = directories.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", ");
+    let action = if removal { "securely delete".to_string() } else { format!("{} and wipe", mode) };
+    let question = format!("This will {} {} files ({} bytes) in {}.", action, files, bytes, names);
+
+    if !io::stdin().is_terminal() {
+        return Err(LockitError::Usage(format!("{} Refusing without confirmation, pass --yes to proceed.", question)));
+    }
+    eprint!("{} Continue? [y/N] ", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(LockitError::Usage("Aborted, nothing was changed.".into())),
+    }
+}
+
+// Created if needed. Canonical, so the walk can recognize it when it lies inside a processed tree.
+fn prepare_output_dir(path: &Path, dry_run: bool) -> error::Result<PathBuf> {
+    let created = if dry_run && !path.exists() { Ok(()) } else { fs::create_dir_all(path) };
+    created
+        .and_then(|()| if path.exists() { fs::canonicalize(path) } else { std::path::absolute(path) })
+        .map_err(|e| LockitError::Io(io::Error::new(e.kind(), format!("Cannot use output directory {}: {}", path.display(), e))))
+}
+
+fn process_removal(paths: Vec<PathBuf>, skip_dod: bool, dry_run: bool) -> Report {
+    let mut report = Report::default();
+    for path in paths {
+        if dry_run && path.exists() {
+            let action = plan::PlannedAction::new(if skip_dod { "delete" } else { "wipe" }, &path);
+            match file_operations::tree_size(&path) {
+                Ok(size) => report.plan(action.bytes(size)),
+                Err(e) => report.fail(&path, e.into()),
             }
+        } else if is_stdio(&path) {
+            report.fail(&path, LockitError::Usage("Standard input cannot be securely deleted".into()));
+        } else if path.is_file() {
+            report.record(&path, secure_delete(&path, skip_dod).map_err(LockitError::Io));
+        } else if path.is_dir() {
+            report.record(&path, secure_delete_directory(&path, skip_dod).map_err(LockitError::Io));
+        } else if path.exists() {
+            report.skip(&path, "not a file or directory");
         } else {
-            debug_print!("Invalid path type: {}", path.display());
+            report.fail(&path, not_found());
         }
     }
+    report
+}
+
+fn not_found() -> LockitError {
+    LockitError::Io(io::Error::new(io::ErrorKind::NotFound, "No such file or directory"))
 }
 
 fn secure_self_destruct(skip_dod: bool) {
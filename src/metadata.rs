@@ -0,0 +1,369 @@
+use std::ffi::OsString;
+use std::fs::{self, File, FileTimes, Permissions};
+use std::io::Read;
+use std::path::{Component, Path};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config;
+use crate::error::{LockitError, Result};
+use crate::names::{name_from_bytes, name_to_bytes};
+
+// The block is `length (u32 LE) || records`, each record `tag (u8) || length (u32 LE) || value`.
+// Unknown tags are skipped, so later versions can add attributes.
+const TAG_NAME: u8 = 0x01;
+const TAG_MODE: u8 = 0x02;
+const TAG_MODIFIED: u8 = 0x03;
+const TAG_ACCESSED: u8 = 0x04;
+const TAG_OWNER: u8 = 0x05;
+const TAG_XATTR: u8 = 0x06;
+
+// The length prefix of the block is checked against this before its buffer is allocated
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+// Original name and attributes of an encrypted file or archived directory. It is written at the
+// start of the compressed plaintext, so it is as private and authenticated as the contents.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    pub name: Option<OsString>,
+    pub mode: Option<u32>,
+    pub modified: Option<SystemTime>,
+    pub accessed: Option<SystemTime>,
+    pub owner: Option<(u32, u32)>,
+    pub xattrs: Vec<(OsString, Vec<u8>)>,
+}
+
+impl Metadata {
+    // Owner and extended attributes are only recorded when enabled in the configuration
+    pub fn read_from(path: &Path) -> Result<Self> {
+        let file_metadata = fs::metadata(path)?;
+        let settings = config::get();
+
+        Ok(Metadata {
+            name: path.file_name().filter(|name| name_to_bytes(name).is_some()).map(OsString::from),
+            mode: Some(permission_bits(&file_metadata)),
+            modified: file_metadata.modified().ok(),
+            accessed: file_metadata.accessed().ok(),
+            owner: if settings.preserve_owner.value { owner(&file_metadata) } else { None },
+            xattrs: if settings.preserve_xattrs.value { read_xattrs(path)? } else { Vec::new() },
+        })
+    }
+
+    // The name to restore, if it is a plain file name. Anything that could point elsewhere,
+    // such as `..` or a path with separators, is rejected.
+    pub fn file_name(&self) -> Result<Option<&Path>> {
+        let Some(name) = &self.name else {
+            return Ok(None);
+        };
+
+        let path = Path::new(name);
+        let mut components = path.components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(component)), None) if component == name.as_os_str() => Ok(Some(path)),
+            _ => Err(LockitError::Corrupt(format!("Invalid file name in metadata: {}", path.display()))),
+        }
+    }
+
+    // Applied to a freshly written output through its open handle. The mode is set after the
+    // owner, as changing the owner clears set-user-ID bits, and the times are set last. Like
+    // tar, the set-user-ID, set-group-ID and sticky bits are only restored along with the owner.
+    pub fn apply_to_file(&self, file: &File) -> Result<()> {
+        let settings = config::get();
+        let mut special_bits = 0;
+        if let (true, Some((uid, gid))) = (settings.preserve_owner.value, self.owner) {
+            set_owner(file, uid, gid)?;
+            special_bits = 0o7000;
+        }
+        if settings.preserve_xattrs.value {
+            for (name, value) in &self.xattrs {
+                set_xattr(file, name, value)?;
+            }
+        }
+        if let Some(mode) = self.mode {
+            file.set_permissions(permissions(file.metadata()?.permissions(), mode & (0o777 | special_bits)))?;
+        }
+        Ok(file.set_times(self.file_times())?)
+    }
+
+    // Archived directories get the same treatment through a read-only handle. Elsewhere
+    // directories cannot be opened, so only the read-only attribute is restored.
+    pub fn apply_to_directory(&self, path: &Path) -> Result<()> {
+        if cfg!(unix) {
+            return self.apply_to_file(&File::open(path)?);
+        }
+        if let Some(mode) = self.mode {
+            fs::set_permissions(path, permissions(fs::metadata(path)?.permissions(), mode & 0o777))?;
+        }
+        Ok(())
+    }
+
+    fn file_times(&self) -> FileTimes {
+        let mut times = FileTimes::new();
+        if let Some(modified) = self.modified {
+            times = times.set_modified(modified);
+        }
+        if let Some(accessed) = self.accessed {
+            times = times.set_accessed(accessed);
+        }
+        times
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut records = Vec::new();
+        if let Some(name) = self.name.as_deref().and_then(name_to_bytes) {
+            push_record(&mut records, TAG_NAME, name);
+        }
+        if let Some(mode) = self.mode {
+            push_record(&mut records, TAG_MODE, &mode.to_le_bytes());
+        }
+        if let Some(modified) = self.modified {
+            push_record(&mut records, TAG_MODIFIED, &encode_time(modified));
+        }
+        if let Some(accessed) = self.accessed {
+            push_record(&mut records, TAG_ACCESSED, &encode_time(accessed));
+        }
+        if let Some((uid, gid)) = self.owner {
+            push_record(&mut records, TAG_OWNER, &[uid.to_le_bytes(), gid.to_le_bytes()].concat());
+        }
+        for (name, value) in &self.xattrs {
+            let Some(name) = name_to_bytes(name) else {
+                continue;
+            };
+            let mut record = (name.len() as u32).to_le_bytes().to_vec();
+            record.extend_from_slice(name);
+            record.extend_from_slice(value);
+            push_record(&mut records, TAG_XATTR, &record);
+        }
+
+        let mut block = (records.len() as u32).to_le_bytes().to_vec();
+        block.extend_from_slice(&records);
+        block
+    }
+
+    // Reads exactly one block from the start of a decrypted payload
+    pub fn read<R: Read + ?Sized>(reader: &mut R) -> Result<Self> {
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_METADATA_SIZE {
+            return Err(LockitError::Corrupt("Metadata block exceeds the maximum size".into()));
+        }
+
+        let mut records = vec![0u8; len];
+        reader.read_exact(&mut records)?;
+        Self::decode(&records)
+    }
+
+    pub fn decode(mut records: &[u8]) -> Result<Self> {
+        let mut metadata = Metadata::default();
+        while !records.is_empty() {
+            let tag = records[0];
+            let (value, rest) = split_prefixed(&records[1..])?;
+            records = rest;
+
+            match tag {
+                TAG_NAME => metadata.name = Some(decode_name(value)?),
+                TAG_MODE => metadata.mode = Some(u32::from_le_bytes(fixed(value)?)),
+                TAG_MODIFIED => metadata.modified = Some(decode_time(value)?),
+                TAG_ACCESSED => metadata.accessed = Some(decode_time(value)?),
+                TAG_OWNER => {
+                    let owner: [u8; 8] = fixed(value)?;
+                    let (uid, gid) = owner.split_at(4);
+                    metadata.owner = Some((u32::from_le_bytes(fixed(uid)?), u32::from_le_bytes(fixed(gid)?)));
+                }
+                TAG_XATTR => {
+                    let (name, value) = split_prefixed(value)?;
+                    metadata.xattrs.push((decode_name(name)?, value.to_vec()));
+                }
+                _ => {}
+            }
+        }
+        Ok(metadata)
+    }
+}
+
+fn decode_name(bytes: &[u8]) -> Result<OsString> {
+    name_from_bytes(bytes.to_vec()).ok_or_else(|| LockitError::Unsupported("Name in metadata is not valid UTF-8".into()))
+}
+
+fn push_record(records: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    records.push(tag);
+    records.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    records.extend_from_slice(value);
+}
+
+// Splits `length (u32 LE) || value || rest`
+fn split_prefixed(data: &[u8]) -> Result<(&[u8], &[u8])> {
+    let truncated = || LockitError::Corrupt("Truncated metadata block".into());
+    let len = data.get(..4).ok_or_else(truncated)?;
+    let len = u32::from_le_bytes(fixed(len)?) as usize;
+    let rest = &data[4..];
+    if len > rest.len() {
+        return Err(truncated());
+    }
+    Ok(rest.split_at(len))
+}
+
+fn fixed<const N: usize>(value: &[u8]) -> Result<[u8; N]> {
+    value.try_into().map_err(|_| LockitError::Corrupt("Invalid metadata record".into()))
+}
+
+// Seconds since the epoch (i64 LE, negative before it) and nanoseconds (u32 LE)
+fn encode_time(time: SystemTime) -> Vec<u8> {
+    let (secs, nanos) = match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => (after.as_secs() as i64, after.subsec_nanos()),
+        Err(e) => {
+            let before = e.duration();
+            match before.subsec_nanos() {
+                0 => (-(before.as_secs() as i64), 0),
+                nanos => (-(before.as_secs() as i64) - 1, 1_000_000_000 - nanos),
+            }
+        }
+    };
+    [secs.to_le_bytes().as_slice(), &nanos.to_le_bytes()].concat()
+}
+
+fn decode_time(value: &[u8]) -> Result<SystemTime> {
+    let value: [u8; 12] = fixed(value)?;
+    let (secs, nanos) = value.split_at(8);
+    let secs = i64::from_le_bytes(fixed(secs)?);
+    let nanos = u32::from_le_bytes(fixed(nanos)?);
+
+    let time = if nanos >= 1_000_000_000 {
+        None
+    } else if secs >= 0 {
+        UNIX_EPOCH.checked_add(Duration::new(secs as u64, nanos))
+    } else {
+        UNIX_EPOCH
+            .checked_sub(Duration::from_secs(secs.unsigned_abs()))
+            .and_then(|time| time.checked_add(Duration::from_nanos(nanos as u64)))
+    };
+    time.ok_or_else(|| LockitError::Corrupt("Invalid time in metadata".into()))
+}
+
+// Unix permission bits, including set-user-ID, set-group-ID and sticky. Elsewhere only the
+// read-only attribute exists, which is mapped to the missing write bits.
+#[cfg(unix)]
+fn permission_bits(metadata: &fs::Metadata) -> u32 {
+    std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o7777
+}
+
+#[cfg(not(unix))]
+fn permission_bits(metadata: &fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() { 0o444 } else { 0o666 }
+}
+
+#[cfg(unix)]
+fn permissions(mut permissions: Permissions, mode: u32) -> Permissions {
+    std::os::unix::fs::PermissionsExt::set_mode(&mut permissions, mode & 0o7777);
+    permissions
+}
+
+#[cfg(not(unix))]
+fn permissions(mut permissions: Permissions, mode: u32) -> Permissions {
+    permissions.set_readonly(mode & 0o222 == 0);
+    permissions
+}
+
+#[cfg(unix)]
+fn owner(metadata: &fs::Metadata) -> Option<(u32, u32)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.uid(), metadata.gid()))
+}
+
+#[cfg(not(unix))]
+fn owner(_metadata: &fs::Metadata) -> Option<(u32, u32)> {
+    None
+}
+
+#[cfg(unix)]
+fn set_owner(file: &File, uid: u32, gid: u32) -> Result<()> {
+    Ok(std::os::unix::fs::fchown(file, Some(uid), Some(gid))?)
+}
+
+#[cfg(not(unix))]
+fn set_owner(_file: &File, _uid: u32, _gid: u32) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn read_xattrs(path: &Path) -> Result<Vec<(OsString, Vec<u8>)>> {
+    if !xattr::SUPPORTED_PLATFORM {
+        return Ok(Vec::new());
+    }
+
+    let mut xattrs = Vec::new();
+    for name in xattr::list(path)? {
+        if let Some(value) = xattr::get(path, &name)? {
+            xattrs.push((name, value));
+        }
+    }
+    Ok(xattrs)
+}
+
+#[cfg(not(unix))]
+fn read_xattrs(_path: &Path) -> Result<Vec<(OsString, Vec<u8>)>> {
+    Ok(Vec::new())
+}
+
+#[cfg(unix)]
+fn set_xattr(file: &File, name: &OsString, value: &[u8]) -> Result<()> {
+    if xattr::SUPPORTED_PLATFORM {
+        xattr::FileExt::set_xattr(file, name, value)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_xattr(_file: &File, _name: &OsString, _value: &[u8]) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const NON_UTF8_NAME: &[u8] = b"bad\xff\xfe";
+
+    #[test]
+    fn non_utf8_metadata_round_trips() {
+        let metadata = Metadata {
+            name: name_from_bytes(NON_UTF8_NAME.to_vec()),
+            mode: Some(0o4750),
+            modified: Some(UNIX_EPOCH + Duration::new(1_700_000_000, 123)),
+            // Before the epoch, with nanoseconds
+            accessed: Some(UNIX_EPOCH - Duration::new(10, 500)),
+            owner: Some((1000, 100)),
+            xattrs: vec![(name_from_bytes(b"user.\xff".to_vec()).unwrap(), b"\x00\xff".to_vec())],
+        };
+
+        let mut block = metadata.encode();
+        block.extend_from_slice(b"payload");
+        let mut reader = Cursor::new(block);
+        assert_eq!(Metadata::read(&mut reader).unwrap(), metadata);
+        assert_eq!(&reader.get_ref()[reader.position() as usize..], b"payload");
+        assert_eq!(metadata.file_name().unwrap(), Some(Path::new(name_from_bytes(NON_UTF8_NAME.to_vec()).unwrap().as_os_str())));
+    }
+
+    #[test]
+    fn names_that_leave_the_directory_are_rejected() {
+        for name in ["..", "a/b", "/etc"] {
+            let metadata = Metadata { name: Some(OsString::from(name)), ..Metadata::default() };
+            assert!(matches!(metadata.file_name(), Err(LockitError::Corrupt(_))), "{}", name);
+        }
+    }
+
+    #[test]
+    fn special_bits_need_the_owner_restored() {
+        let temp = tempfile::tempdir().unwrap();
+        let file = File::create(temp.path().join("file")).unwrap();
+        Metadata { mode: Some(0o7755), ..Metadata::default() }.apply_to_file(&file).unwrap();
+        assert_eq!(permission_bits(&file.metadata().unwrap()), 0o755);
+    }
+
+    #[test]
+    fn truncated_records_are_rejected() {
+        let encoded = Metadata { mode: Some(0o644), ..Metadata::default() }.encode();
+        assert!(matches!(Metadata::decode(&encoded[4..encoded.len() - 1]), Err(LockitError::Corrupt(_))));
+    }
+}
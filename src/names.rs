@@ -0,0 +1,168 @@
+use aes_gcm::aead::{AeadInPlace, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use data_encoding::BASE32_DNSSEC;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::ffi::{OsStr, OsString};
+
+use crate::crypto::{self, Keyring};
+use crate::error::{LockitError, Result};
+use crate::header::Compression;
+
+// Holds the key for the encrypted names in its directory, wrapped under the key of the run.
+// Further keys are numbered, as in `.lockit-names.1`.
+pub const NAME_KEY_FILE: &str = ".lockit-names";
+
+// Encrypted names that do not fit in a path component are replaced by `lockit-long-<hash>`, and
+// the encrypted name is kept in the sidecar `lockit-long-<hash>.name`
+pub const LONG_NAME_PREFIX: &str = "lockit-long-";
+pub const LONG_NAME_EXTENSION: &str = "name";
+
+// Longest path component on common file systems, in bytes
+pub const MAX_NAME_LEN: usize = 255;
+
+const KEY_SIZE: usize = 32;
+const SIV_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+const LONG_NAME_HASH_SIZE: usize = 16;
+
+const NAME_ENCRYPTION_INFO: &[u8] = b"lockit name encryption";
+const NAME_SIV_INFO: &[u8] = b"lockit name siv";
+
+// Random per-directory key. Names are encrypted deterministically under it, so they need neither
+// a salt nor a header of their own, and the same name always encrypts to the same string.
+#[derive(PartialEq)]
+pub struct NameKey {
+    key: [u8; KEY_SIZE],
+}
+
+impl NameKey {
+    pub fn generate() -> Self {
+        let mut key = [0u8; KEY_SIZE];
+        OsRng.fill_bytes(&mut key);
+        NameKey { key }
+    }
+
+    // The contents of a name key file: the key encrypted like any other data, with a full header
+    pub fn seal(&self, keyring: &Keyring) -> Result<Vec<u8>> {
+        crypto::encrypt_data(&self.key, keyring, Compression::None, 0)
+    }
+
+    pub fn open(data: &[u8], keyring: &Keyring) -> Result<Self> {
+        let (_, key) = crypto::decrypt_data(data, keyring)?;
+        let key = key.try_into().map_err(|_| LockitError::Corrupt("Invalid name key".into()))?;
+        Ok(NameKey { key })
+    }
+
+    // AES-256-GCM under a synthetic IV, an HMAC of the name, as in SIV mode. The result is
+    // `siv (12) || ciphertext || tag (16)` in lowercase base32, which case-insensitive file
+    // systems keep intact.
+    pub fn encrypt_name(&self, name: &[u8]) -> Result<String> {
+        let (cipher, siv_key) = self.subkeys();
+        let siv = synthetic_iv(&siv_key, name);
+
+        let mut buffer = name.to_vec();
+        cipher
+            .encrypt_in_place(Nonce::from_slice(&siv), b"", &mut buffer)
+            .map_err(|_| LockitError::Io(std::io::Error::other("Name encryption failed")))?;
+        Ok(BASE32_DNSSEC.encode(&[siv.as_slice(), &buffer].concat()))
+    }
+
+    pub fn decrypt_name(&self, encoded: &str) -> Result<Vec<u8>> {
+        let corrupt = || LockitError::Corrupt(format!("Encrypted name {} is damaged or was not encrypted with this key", encoded));
+        let data = BASE32_DNSSEC.decode(encoded.as_bytes()).map_err(|_| corrupt())?;
+        if data.len() < SIV_SIZE + TAG_SIZE {
+            return Err(corrupt());
+        }
+
+        let (cipher, siv_key) = self.subkeys();
+        let (siv, sealed) = data.split_at(SIV_SIZE);
+        let mut name = sealed.to_vec();
+        cipher.decrypt_in_place(Nonce::from_slice(siv), b"", &mut name).map_err(|_| corrupt())?;
+        if synthetic_iv(&siv_key, &name) != siv {
+            return Err(corrupt());
+        }
+        Ok(name)
+    }
+
+    fn subkeys(&self) -> (Aes256Gcm, [u8; KEY_SIZE]) {
+        let hkdf = Hkdf::<Sha256>::new(None, &self.key);
+        let mut encryption_key = [0u8; KEY_SIZE];
+        let mut siv_key = [0u8; KEY_SIZE];
+        hkdf.expand(NAME_ENCRYPTION_INFO, &mut encryption_key).unwrap();
+        hkdf.expand(NAME_SIV_INFO, &mut siv_key).unwrap();
+        (Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&encryption_key)), siv_key)
+    }
+}
+
+fn synthetic_iv(siv_key: &[u8; KEY_SIZE], name: &[u8]) -> [u8; SIV_SIZE] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(siv_key).unwrap();
+    mac.update(name);
+    mac.finalize().into_bytes()[..SIV_SIZE].try_into().unwrap()
+}
+
+// Whether a name (without its `.lockit` or `.dir.lockit` suffix) has the shape of an encrypted name
+pub fn looks_encrypted(name: &str) -> bool {
+    name.starts_with(LONG_NAME_PREFIX)
+        || BASE32_DNSSEC.decode(name.as_bytes()).is_ok_and(|data| data.len() > SIV_SIZE + TAG_SIZE)
+}
+
+pub fn long_name(encoded: &str) -> String {
+    let hash = Sha256::digest(encoded.as_bytes());
+    format!("{}{}", LONG_NAME_PREFIX, BASE32_DNSSEC.encode(&hash[..LONG_NAME_HASH_SIZE]))
+}
+
+// Names are raw bytes on Unix, so any name round-trips. Elsewhere they are stored as UTF-8, which
+// covers every name that is valid Unicode.
+#[cfg(unix)]
+pub fn name_to_bytes(name: &OsStr) -> Option<&[u8]> {
+    Some(std::os::unix::ffi::OsStrExt::as_bytes(name))
+}
+
+#[cfg(not(unix))]
+pub fn name_to_bytes(name: &OsStr) -> Option<&[u8]> {
+    name.to_str().map(str::as_bytes)
+}
+
+#[cfg(unix)]
+pub fn name_from_bytes(bytes: Vec<u8>) -> Option<OsString> {
+    Some(std::os::unix::ffi::OsStringExt::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+pub fn name_from_bytes(bytes: Vec<u8>) -> Option<OsString> {
+    String::from_utf8(bytes).ok().map(OsString::from)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    const NON_UTF8_NAME: &[u8] = b"bad\xff\xfe";
+
+    #[test]
+    fn non_utf8_name_round_trips() {
+        let name_key = NameKey::generate();
+        let encrypted = name_key.encrypt_name(NON_UTF8_NAME).unwrap();
+        assert!(looks_encrypted(&encrypted));
+        assert_eq!(name_key.decrypt_name(&encrypted).unwrap(), NON_UTF8_NAME);
+
+        let name = name_from_bytes(NON_UTF8_NAME.to_vec()).unwrap();
+        assert_eq!(name_to_bytes(&name), Some(NON_UTF8_NAME));
+    }
+
+    #[test]
+    fn names_encrypt_deterministically_per_key() {
+        let name_key = NameKey::generate();
+        assert_eq!(name_key.encrypt_name(b"report.pdf").unwrap(), name_key.encrypt_name(b"report.pdf").unwrap());
+        assert_ne!(name_key.encrypt_name(b"report.pdf").unwrap(), NameKey::generate().encrypt_name(b"report.pdf").unwrap());
+    }
+
+    #[test]
+    fn name_from_another_key_is_rejected() {
+        let encrypted = NameKey::generate().encrypt_name(NON_UTF8_NAME).unwrap();
+        assert!(matches!(NameKey::generate().decrypt_name(&encrypted), Err(LockitError::Corrupt(_))));
+    }
+}
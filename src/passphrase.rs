@@ -0,0 +1,70 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::DEFAULT_PASSPHRASE;
+
+pub const PASSPHRASE_ENV: &str = "LOCKIT_PASSPHRASE";
+
+// Where the passphrase for a run comes from. There is no implicit fallback: the passphrase
+// compiled into the binary is only used when explicitly requested.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PassphraseSource {
+    Prompt,
+    File(PathBuf),
+    Fd(i32),
+    Environment,
+    BuiltIn,
+}
+
+impl PassphraseSource {
+    pub fn read(&self, confirm: bool) -> io::Result<String> {
+        let passphrase = match self {
+            PassphraseSource::Prompt => {
+                let alternatives = format!("use --passphrase-file, --passphrase-fd or {}", PASSPHRASE_ENV);
+                prompt("Enter the password: ", confirm, &alternatives)?
+            }
+            PassphraseSource::File(path) => read_first_line(path)?,
+            PassphraseSource::Fd(fd) => read_fd(*fd)?,
+            PassphraseSource::Environment => std::env::var(PASSPHRASE_ENV)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not set or not valid UTF-8", PASSPHRASE_ENV)))?,
+            PassphraseSource::BuiltIn => {
+                eprintln!("Warning: using the passphrase compiled into lockit. Anyone with a copy of lockit or its source can decrypt these files.");
+                DEFAULT_PASSPHRASE.to_string()
+            }
+        };
+
+        if passphrase.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The passphrase is empty"));
+        }
+        Ok(passphrase)
+    }
+}
+
+// Prompts on the terminal, asking twice when the passphrase is about to protect new data. Without
+// a terminal the error names the `alternatives` to pass the passphrase some other way.
+pub fn prompt(message: &str, confirm: bool, alternatives: &str) -> io::Result<String> {
+    let passphrase = rpassword::prompt_password(message)
+        .map_err(|e| io::Error::new(e.kind(), format!("Cannot prompt for a passphrase ({}), {}", e, alternatives)))?;
+
+    if confirm && rpassword::prompt_password("Confirm the password: ")? != passphrase {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "The passphrases do not match"));
+    }
+    Ok(passphrase)
+}
+
+fn read_first_line(path: &Path) -> io::Result<String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Cannot read passphrase from {}: {}", path.display(), e)))?;
+    Ok(contents.lines().next().unwrap_or_default().to_string())
+}
+
+#[cfg(unix)]
+fn read_fd(fd: i32) -> io::Result<String> {
+    read_first_line(&PathBuf::from(format!("/dev/fd/{}", fd)))
+}
+
+#[cfg(not(unix))]
+fn read_fd(_fd: i32) -> io::Result<String> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "--passphrase-fd is not supported on this platform"))
+}
@@ -1,11 +1,162 @@
-use std::io;
+use std::io::{self, Read, Write};
 
-pub fn encrypt_data_via_pipe(input: &[u8], password: &str) -> Result<Vec<u8>, io::Error> {
-    let compressed = super::compression::compress_data(input).unwrap();
-    super::crypto::encrypt_data(&compressed, password).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+use crate::crypto::{self, Keyring, CHUNK_SIZE};
+use crate::error::{LockitError, Result};
+use crate::header::{Compression, EXT_RECORDS};
+
+// Each record is `length (u32 BE) || sealed compressed data`. The top bit of the length marks the
+// empty end-of-stream record; the record sequence number lives in the AEAD nonce counter, so
+// reordered, replayed or dropped records fail authentication.
+const FINAL_RECORD: u32 = 0x8000_0000;
+
+// Compressed input can slightly outgrow the read buffer, and the AEAD tag is added on top
+const MAX_RECORD_SIZE: u32 = (CHUNK_SIZE + CHUNK_SIZE / 2) as u32;
+
+// Largest record size a reader accepts from a stream header
+const RECORD_SIZE_LIMIT: u32 = 16 * 1024 * 1024;
+
+pub fn encrypt_pipe<R: Read, W: Write>(mut input: R, mut output: W, keyring: &Keyring) -> Result<()> {
+    let extension = (EXT_RECORDS, MAX_RECORD_SIZE.to_le_bytes().to_vec());
+    let mut records = crypto::start_stream(&mut output, keyring, Compression::Zstd, 0, vec![extension])?;
+    output.flush()?;
+
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    loop {
+        // Every read is sealed and flushed right away, so interactive streams are not held back
+        let bytes_read = match input.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut record = super::compression::compress_data(&buffer[..bytes_read])?;
+        records.seal(&mut record, false)?;
+        write_record(&mut output, &record, false)?;
+    }
+
+    let mut record = Vec::new();
+    records.seal(&mut record, true)?;
+    write_record(&mut output, &record, true)
+}
+
+pub fn decrypt_pipe<R: Read, W: Write>(mut input: R, mut output: W, keyring: &Keyring) -> Result<()> {
+    let (header, mut records) = crypto::open_stream(&mut input, keyring, EXT_RECORDS)?;
+    let max_record_size = header.record_size_limit().filter(|&size| size <= RECORD_SIZE_LIMIT).ok_or_else(|| {
+        LockitError::Corrupt("Invalid pipe record size limit".into())
+    })?;
+
+    loop {
+        let mut prefix = [0u8; 4];
+        if crypto::read_full(&mut input, &mut prefix)? != prefix.len() {
+            return Err(LockitError::Corrupt("Pipe stream ended without an end-of-stream record".into()));
+        }
+
+        let prefix = u32::from_be_bytes(prefix);
+        let last = prefix & FINAL_RECORD != 0;
+        let len = prefix & !FINAL_RECORD;
+        if len > max_record_size {
+            return Err(LockitError::Corrupt("Pipe record exceeds the maximum size".into()));
+        }
+
+        let mut record = vec![0u8; len as usize];
+        if crypto::read_full(&mut input, &mut record)? != record.len() {
+            return Err(LockitError::Corrupt("Pipe stream is truncated".into()));
+        }
+        records.open(&mut record, last)?;
+
+        if last {
+            return Ok(output.flush()?);
+        }
+
+        let data = super::compression::decompress_with(header.compression, record)?;
+        output.write_all(&data)?;
+        output.flush()?;
+    }
+}
+
+fn write_record<W: Write>(output: &mut W, record: &[u8], last: bool) -> Result<()> {
+    if record.len() as u32 > MAX_RECORD_SIZE {
+        return Err(LockitError::Io(io::Error::other("Pipe record exceeds the maximum size")));
+    }
+
+    let prefix = record.len() as u32 | if last { FINAL_RECORD } else { 0 };
+    output.write_all(&prefix.to_be_bytes())?;
+    output.write_all(record)?;
+    Ok(output.flush()?)
 }
 
-pub fn decrypt_data_via_pipe(input: &[u8], password: &str) -> Result<Vec<u8>, io::Error> {
-    let decrypted = super::crypto::decrypt_data(input, password).map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()));
-    super::compression::decompress_data(&decrypted.unwrap())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header;
+    use std::io::Cursor;
+    use std::ops::Range;
+
+    // Hands out a single byte per read, the way a slow pipe may
+    struct OneByte<R>(R);
+
+    impl<R: Read> Read for OneByte<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    fn keyring() -> Keyring {
+        Keyring::new(None).with_key([0x42; 32])
+    }
+
+    // Each part arrives in a read of its own, so each one becomes a record
+    fn encrypted_stream() -> Vec<u8> {
+        let input = (&b"first"[..]).chain(&b"second"[..]).chain(&b"third"[..]);
+        let mut stream = Vec::new();
+        encrypt_pipe(input, &mut stream, &keyring()).unwrap();
+        stream
+    }
+
+    fn records(stream: &[u8]) -> Vec<Range<usize>> {
+        let (_, mut start) = header::parse(stream).unwrap();
+        let mut records = Vec::new();
+        while start < stream.len() {
+            let len = u32::from_be_bytes(stream[start..start + 4].try_into().unwrap()) & !FINAL_RECORD;
+            records.push(start..start + 4 + len as usize);
+            start += 4 + len as usize;
+        }
+        records
+    }
+
+    fn decrypt(input: impl Read) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        decrypt_pipe(input, &mut output, &keyring())?;
+        Ok(output)
+    }
+
+    #[test]
+    fn records_are_read_whatever_the_read_sizes() {
+        let stream = encrypted_stream();
+        assert_eq!(records(&stream).len(), 4);
+        assert_eq!(decrypt(Cursor::new(&stream)).unwrap(), b"firstsecondthird");
+        assert_eq!(decrypt(OneByte(Cursor::new(&stream))).unwrap(), b"firstsecondthird");
+    }
+
+    #[test]
+    fn swapped_records_are_rejected() {
+        let stream = encrypted_stream();
+        let records = records(&stream);
+        let mut swapped = stream[..records[0].start].to_vec();
+        swapped.extend_from_slice(&stream[records[1].clone()]);
+        swapped.extend_from_slice(&stream[records[0].clone()]);
+        swapped.extend_from_slice(&stream[records[2].start..]);
+        assert!(matches!(decrypt(Cursor::new(&swapped)), Err(LockitError::Corrupt(_))));
+        assert!(matches!(decrypt(OneByte(Cursor::new(&swapped))), Err(LockitError::Corrupt(_))));
+    }
+
+    #[test]
+    fn dropped_final_record_is_rejected() {
+        let stream = encrypted_stream();
+        let truncated = &stream[..records(&stream)[3].start];
+        assert!(matches!(decrypt(Cursor::new(truncated)), Err(LockitError::Corrupt(_))));
+        assert!(matches!(decrypt(OneByte(Cursor::new(truncated))), Err(LockitError::Corrupt(_))));
+    }
 }
@@ -0,0 +1,87 @@
+use serde::Serialize;
+use std::path::Path;
+
+use crate::error::Report;
+
+// A step a run would take, as listed by --dry-run instead of being carried out
+#[derive(Serialize)]
+pub struct PlannedAction {
+    pub action: &'static str,
+    pub source: String,
+    pub target: Option<String>,
+    pub bytes: Option<u64>,
+    // The --on-conflict policy applied because the target already exists
+    pub conflict: Option<String>,
+}
+
+impl PlannedAction {
+    pub fn new(action: &'static str, source: &Path) -> Self {
+        PlannedAction { action, source: source.display().to_string(), target: None, bytes: None, conflict: None }
+    }
+
+    pub fn to(mut self, target: &Path) -> Self {
+        self.target = Some(target.display().to_string());
+        self
+    }
+
+    pub fn bytes(mut self, bytes: u64) -> Self {
+        self.bytes = Some(bytes);
+        self
+    }
+
+    pub fn conflict(mut self, policy: Option<String>) -> Self {
+        self.conflict = policy;
+        self
+    }
+}
+
+#[derive(Serialize)]
+struct JsonPlan<'a> {
+    actions: &'a [PlannedAction],
+    skipped: Vec<JsonEntry>,
+    failed: Vec<JsonEntry>,
+}
+
+#[derive(Serialize)]
+struct JsonEntry {
+    path: String,
+    reason: String,
+}
+
+// Skips and failures were already reported on stderr as they happened; the JSON plan repeats them
+// so it is complete on its own
+pub fn print(report: &Report, json: bool) {
+    if json {
+        let entry = |path: &Path, reason: String| JsonEntry { path: path.display().to_string(), reason };
+        let plan = JsonPlan {
+            actions: &report.planned,
+            skipped: report.skipped.iter().map(|(path, reason)| entry(path, reason.clone())).collect(),
+            failed: report.failures.iter().map(|(path, e)| entry(path, e.to_string())).collect(),
+        };
+        println!("{}", serde_json::to_string_pretty(&plan).expect("The plan serializes to JSON"));
+        return;
+    }
+
+    for action in &report.planned {
+        let mut line = format!("{:<8} {}", action.action, action.source);
+        if let Some(target) = &action.target {
+            line.push_str(&format!(" -> {}", target));
+        }
+        if let Some(bytes) = action.bytes {
+            line.push_str(&format!(" ({} bytes)", bytes));
+        }
+        if let Some(policy) = &action.conflict {
+            line.push_str(&format!(" [exists, --on-conflict={}]", policy));
+        }
+        println!("{}", line);
+    }
+
+    let wiped: u64 = report.planned.iter().filter(|action| action.action == "wipe").filter_map(|action| action.bytes).sum();
+    println!(
+        "\nDry run, nothing was changed: {} actions, {} bytes to wipe, {} skipped, {} failed",
+        report.planned.len(),
+        wiped,
+        report.skipped.len(),
+        report.failures.len()
+    );
+}
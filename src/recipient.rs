@@ -0,0 +1,137 @@
+use aes_gcm::aead::{Aead, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::Path;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+pub const PUBLIC_KEY_PREFIX: &str = "lockit-pk-";
+pub const SECRET_KEY_PREFIX: &str = "LOCKIT-SK-";
+
+const KEY_ID_SIZE: usize = 8;
+const WRAPPED_KEY_SIZE: usize = 32 + 16;
+pub const STANZA_SIZE: usize = KEY_ID_SIZE + 32 + WRAPPED_KEY_SIZE;
+
+const WRAP_KEY_INFO: &[u8] = b"lockit x25519 file key";
+
+pub fn generate_identity() -> StaticSecret {
+    StaticSecret::random_from_rng(OsRng)
+}
+
+pub fn encode_public_key(public_key: &PublicKey) -> String {
+    format!("{}{}", PUBLIC_KEY_PREFIX, hex::encode(public_key.as_bytes()))
+}
+
+pub fn encode_identity(identity: &StaticSecret) -> String {
+    format!(
+        "# created by lockit keygen\n# public key: {}\n{}{}\n",
+        encode_public_key(&PublicKey::from(identity)),
+        SECRET_KEY_PREFIX,
+        hex::encode(identity.as_bytes())
+    )
+}
+
+pub fn parse_public_key(encoded: &str) -> io::Result<PublicKey> {
+    encoded
+        .trim()
+        .strip_prefix(PUBLIC_KEY_PREFIX)
+        .and_then(decode_key)
+        .map(PublicKey::from)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid recipient public key: {}", encoded)))
+}
+
+// Identity files hold one secret key per line; blank lines and `#` comments are ignored
+pub fn read_identity_file(path: &Path) -> io::Result<Vec<StaticSecret>> {
+    let contents = fs::read_to_string(path)?;
+    let identities = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.strip_prefix(SECRET_KEY_PREFIX)
+                .and_then(decode_key)
+                .map(StaticSecret::from)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid identity in {}", path.display())))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    if identities.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("No identities found in {}", path.display())));
+    }
+    Ok(identities)
+}
+
+pub fn key_id(public_key: &PublicKey) -> [u8; KEY_ID_SIZE] {
+    let digest = Sha256::digest(public_key.as_bytes());
+    let mut id = [0u8; KEY_ID_SIZE];
+    id.copy_from_slice(&digest[..KEY_ID_SIZE]);
+    id
+}
+
+// A stanza is `recipient key id (8) || ephemeral public key (32) || wrapped file key (48)`
+pub fn wrap_file_key(file_key: &[u8; 32], recipient: &PublicKey) -> io::Result<Vec<u8>> {
+    let ephemeral = StaticSecret::random_from_rng(OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let shared = ephemeral.diffie_hellman(recipient);
+    if !shared.was_contributory() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Recipient public key is not usable"));
+    }
+
+    let cipher = wrap_cipher(shared.as_bytes(), &ephemeral_public, recipient);
+    let wrapped = cipher
+        .encrypt(Nonce::from_slice(&[0u8; 12]), file_key.as_slice())
+        .map_err(|_| io::Error::other("Failed to wrap file key"))?;
+
+    let mut stanza = Vec::with_capacity(STANZA_SIZE);
+    stanza.extend_from_slice(&key_id(recipient));
+    stanza.extend_from_slice(ephemeral_public.as_bytes());
+    stanza.extend_from_slice(&wrapped);
+    Ok(stanza)
+}
+
+// Stanzas name their recipient by key id, so files can be matched to identities without one
+pub fn stanza_key_id(stanza: &[u8]) -> Option<&[u8]> {
+    stanza.get(..KEY_ID_SIZE).filter(|_| stanza.len() == STANZA_SIZE)
+}
+
+pub fn unwrap_file_key(stanza: &[u8], identity: &StaticSecret) -> Option<[u8; 32]> {
+    if stanza.len() != STANZA_SIZE {
+        return None;
+    }
+
+    let public_key = PublicKey::from(identity);
+    let (id, rest) = stanza.split_at(KEY_ID_SIZE);
+    if id != key_id(&public_key) {
+        return None;
+    }
+
+    let (ephemeral_public, wrapped) = rest.split_at(32);
+    let ephemeral_public = PublicKey::from(<[u8; 32]>::try_from(ephemeral_public).ok()?);
+    let shared = identity.diffie_hellman(&ephemeral_public);
+    if !shared.was_contributory() {
+        return None;
+    }
+
+    let cipher = wrap_cipher(shared.as_bytes(), &ephemeral_public, &public_key);
+    let file_key = cipher.decrypt(Nonce::from_slice(&[0u8; 12]), wrapped).ok()?;
+    file_key.try_into().ok()
+}
+
+// Every stanza uses a fresh ephemeral key, so the derived wrapping key is never reused and a fixed
+// nonce is safe.
+fn wrap_cipher(shared: &[u8], ephemeral_public: &PublicKey, recipient: &PublicKey) -> Aes256Gcm {
+    let mut salt = Vec::with_capacity(64);
+    salt.extend_from_slice(ephemeral_public.as_bytes());
+    salt.extend_from_slice(recipient.as_bytes());
+
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared);
+    let mut key = [0u8; 32];
+    hkdf.expand(WRAP_KEY_INFO, &mut key).unwrap();
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+}
+
+fn decode_key(encoded: &str) -> Option<[u8; 32]> {
+    hex::decode(encoded).ok()?.try_into().ok()
+}